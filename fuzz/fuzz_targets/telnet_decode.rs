@@ -0,0 +1,26 @@
+#![no_main]
+
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+use tokio_util::codec::Decoder;
+
+use thermite::protocols::telnet::codec::{TelnetCodec, TelnetEvent};
+
+// Whatever a client sends must never panic the parser or the codec. The first byte picks where
+// the input is split into two reads, so partial commands and subnegotiations get exercised too.
+fuzz_target!(|data: &[u8]| {
+    let mut src = BytesMut::from(data);
+    while TelnetEvent::parse(&mut src).is_some() {}
+
+    let (split, data) = match data.split_first() {
+        Some((split, rest)) => ((*split as usize).min(rest.len()), rest),
+        None => return
+    };
+    let mut codec = TelnetCodec::new(6);
+    // So an SB MCCP3 in the input reaches the inflate path.
+    codec.set_mccp3_agreed(true);
+    for chunk in [&data[..split], &data[split..]] {
+        let mut src = BytesMut::from(chunk);
+        while let Ok(Some(_)) = codec.decode(&mut src) {}
+    }
+});
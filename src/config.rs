@@ -0,0 +1,177 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs
+};
+
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+
+use serde::{Serialize, Deserialize};
+use serde_json::Value as JsonValue;
+
+use crate::protocols::telnet::quirks::{self, ClientQuirk};
+
+// Settings loaded from config.toml. Every section is optional so that a missing or partial
+// file still gives a working portal.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct Config {
+    pub oob: OobConfig,
+    pub telnet: TelnetConfig,
+    pub proxy: ProxyConfig,
+    pub tls: TlsConfig,
+    // MSSP variables to report until the game sends its own, e.g. NAME. Whatever the game
+    // sends takes precedence.
+    pub mssp: HashMap<String, JsonValue>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct OobConfig {
+    // Text templates for OOB commands sent to clients that have no OOB protocol at all, keyed
+    // by command name. {name} is replaced by the matching kwarg and {0}, {1}... by args.
+    // Commands without a template are dropped and the game is told about it.
+    pub fallback: HashMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct TlsConfig {
+    // How long a client gets to finish a TLS handshake, on the implicit TLS ports, after a
+    // sniffed ClientHello, or during STARTTLS. Clients that take longer are dropped.
+    pub handshake_timeout_ms: u64,
+}
+
+impl Default for TlsConfig {
+    fn default() -> Self {
+        Self {
+            handshake_timeout_ms: 10000
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct TelnetConfig {
+    // zlib level (0-9) used for MCCP2.
+    pub compression_level: u32,
+    // Encodings offered during CHARSET negotiation, most preferred first.
+    pub charsets: Vec<String>,
+    // How long to wait for a TLS ClientHello on the plain telnet port before assuming plain
+    // telnet. Only used when TLS is configured.
+    pub tls_sniff_timeout_ms: u64,
+    // Disconnect clients that have sent nothing at all for this long. 0 turns it off.
+    pub idle_timeout_secs: u64,
+    // Send idle_warning_message this many seconds before the idle disconnect. 0 skips the warning.
+    pub idle_warning_secs: u64,
+    pub idle_warning_message: String,
+    // Keeps NAT and firewall state alive on quiet connections, and finds dead sockets.
+    pub keepalive: KeepaliveMode,
+    pub keepalive_secs: u64,
+    // Wrap text to the client's NAWS width. Games can change this per client with the "wrap"
+    // client_option, or per message with a "wrap" kwarg.
+    pub wrap: bool,
+    // Used for wrapping when the client never reported its size.
+    pub wrap_width: u16,
+    // Which byte sequences end a line of input.
+    pub line_endings: Vec<LineEnding>,
+    // Longer input lines are discarded, in bytes. 0 means no limit.
+    pub max_line_length: usize,
+    // TOML file of [[quirk]] entries giving known clients capabilities they don't report. Empty
+    // means only the built-in list is used.
+    pub quirks_file: String,
+    // Loaded from quirks_file at startup.
+    #[serde(skip, default = "quirks::defaults")]
+    pub quirks: Vec<ClientQuirk>,
+    // Options the plain telnet listener won't negotiate, by name ("mccp2", "mxp", ...).
+    pub disabled_options: Vec<String>,
+    // The same for the --telnet-tls listener.
+    pub tls_disabled_options: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum KeepaliveMode {
+    None,
+    // IAC NOP every keepalive_secs. Clients ignore it, but a dead socket makes the write fail.
+    Nop,
+    // TCP keepalive probes from the OS, starting after keepalive_secs of silence.
+    Tcp
+}
+
+// RFC 854 ends lines with CR LF and sends a lone carriage return as CR NUL. Plenty of clients
+// send a bare CR or LF instead.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LineEnding {
+    CrLf,
+    CrNul,
+    Cr,
+    Lf
+}
+
+impl Default for TelnetConfig {
+    fn default() -> Self {
+        Self {
+            compression_level: 9,
+            charsets: vec!["UTF-8".to_string(), "ISO-8859-1".to_string(), "CP437".to_string(), "US-ASCII".to_string()],
+            tls_sniff_timeout_ms: 250,
+            idle_timeout_secs: 60 * 30,
+            idle_warning_secs: 60,
+            idle_warning_message: "You have been idle for a long time and will be disconnected in a minute.\r\n".to_string(),
+            keepalive: KeepaliveMode::Nop,
+            keepalive_secs: 60,
+            wrap: false,
+            wrap_width: 78,
+            line_endings: vec![LineEnding::CrLf, LineEnding::CrNul, LineEnding::Cr, LineEnding::Lf],
+            max_line_length: 4096,
+            quirks_file: "quirks.toml".to_string(),
+            quirks: quirks::defaults(),
+            disabled_options: Vec::new(),
+            tls_disabled_options: Vec::new()
+        }
+    }
+}
+
+// HAProxy PROXY protocol support. A listener with it switched on expects a v1 or v2 header from
+// peers in trusted and takes the client address from it. Everyone else is taken at face value,
+// so a client can't spoof its address by sending a header of its own.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(default)]
+pub struct ProxyConfig {
+    pub telnet: bool,
+    pub link: bool,
+    pub web: bool,
+    // Source networks of the load balancers, in CIDR notation.
+    pub trusted: Vec<IpNet>,
+    // How long a trusted peer gets to send its header before the connection is dropped.
+    pub timeout_ms: u64,
+}
+
+impl Default for ProxyConfig {
+    fn default() -> Self {
+        Self {
+            telnet: false,
+            link: false,
+            web: false,
+            trusted: Vec::new(),
+            timeout_ms: 5000
+        }
+    }
+}
+
+impl ProxyConfig {
+    pub fn trusts(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.trusted.iter().any(|net| net.contains(&ip))
+    }
+}
+
+impl Config {
+    pub fn load(path: &str) -> Result<Self, Box<dyn Error>> {
+        let data = fs::read_to_string(path)?;
+        Ok(toml::from_str(&data)?)
+    }
+}
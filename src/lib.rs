@@ -3,11 +3,16 @@ pub mod protocols;
 pub mod util;
 pub mod portal;
 pub mod msg;
+pub mod config;
 
 use once_cell::sync::Lazy;
 use std::sync::Mutex;
 use tokio::{sync::mpsc::{Sender}};
 use crate::msg::Msg2Portal;
+use crate::config::Config;
+use crate::protocols::telnet::mssp::MsspCache;
 
 pub static IS_TLS_ENABLED: Lazy<Mutex<bool>> = Lazy::new(|| Mutex::new(false));
-pub static TX_PORTAL: Lazy<Mutex<Option<Sender<Msg2Portal>>>> = Lazy::new(|| Mutex::new(None));
\ No newline at end of file
+pub static TX_PORTAL: Lazy<Mutex<Option<Sender<Msg2Portal>>>> = Lazy::new(|| Mutex::new(None));
+pub static CONFIG: Lazy<Mutex<Config>> = Lazy::new(|| Mutex::new(Config::default()));
+pub static MSSP: Lazy<Mutex<MsspCache>> = Lazy::new(|| Mutex::new(MsspCache::default()));
\ No newline at end of file
@@ -7,19 +7,24 @@ use std::sync::Arc;
 use clap::{Parser};
 use futures::future::join_all;
 
-use tracing::{error, info, Level};
+use tracing::{error, info, warn, Level};
 use tracing_subscriber;
 
 use thermite::{
     networking::{
         link::LinkAcceptor,
         telnet::TelnetAcceptor,
+        tls::load_tls_acceptor,
         web::run_warp
     },
+    protocols::telnet::{options::{option_code, TelnetOptionRegistry}, quirks},
     IS_TLS_ENABLED,
-    TX_PORTAL
+    TX_PORTAL,
+    CONFIG
 };
 
+use thermite::config::Config;
+
 use thermite::portal::Portal;
 
 
@@ -33,6 +38,9 @@ pub struct Args {
     #[arg(short, long, value_name = "ip:port", default_value = "0.0.0.0:1280", help = "Sets the external Telnet IpAddr and u16 port")]
     pub telnet: SocketAddr,
 
+    #[arg(long, value_name = "ip:port", help = "Sets an external IpAddr and u16 port for Telnet over implicit TLS")]
+    pub telnet_tls: Option<SocketAddr>,
+
     #[arg(short, long, value_name = "ip:port", default_value = "0.0.0.0:8000", help = "Sets the external HTTP/WebSocket IpAddr and u16 port")]
     pub web: SocketAddr,
 
@@ -41,9 +49,24 @@ pub struct Args {
 
     #[arg(short, long, value_name = "path", help = "Sets the file path to a .key file for TLS")]
     pub key: Option<String>,
+
+    #[arg(short, long, value_name = "path", default_value = "config.toml", help = "Sets the file path to the portal's config.toml")]
+    pub config: String,
 }
 
 
+// The default options, less the ones a listener's config turns off.
+fn listener_options(disabled: &[String]) -> TelnetOptionRegistry {
+    let mut options = TelnetOptionRegistry::with_defaults();
+    for name in disabled {
+        match option_code(name) {
+            Some(op) => options.remove(op),
+            None => warn!("Unknown telnet option '{}' in config, ignoring it", name)
+        }
+    }
+    options
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
     tracing_subscriber::fmt()
@@ -54,6 +77,32 @@ async fn main() -> Result<(), Box<dyn Error>> {
 
     let args: Args = Args::parse();
 
+    let mut config = match Config::load(&args.config) {
+        Ok(config) => config,
+        Err(e) => {
+            warn!("Could not load config from {}, using defaults: {}", args.config, e);
+            Config::default()
+        }
+    };
+    if !config.telnet.quirks_file.is_empty() {
+        match quirks::load(&config.telnet.quirks_file) {
+            Ok(q) => config.telnet.quirks = q,
+            Err(e) => warn!("Could not load client quirks from {}, using built-in list: {}", config.telnet.quirks_file, e)
+        }
+    }
+    let telnet_options = listener_options(&config.telnet.disabled_options);
+    let telnet_tls_options = listener_options(&config.telnet.tls_disabled_options);
+    *CONFIG.lock().unwrap() = config;
+
+    let tls_acceptor = match (&args.pem, &args.key) {
+        (Some(pem), Some(key)) => {
+            let acceptor = load_tls_acceptor(pem, key)?;
+            *IS_TLS_ENABLED.lock().unwrap() = true;
+            Some(acceptor)
+        },
+        _ => None
+    };
+
     let mut portal = Portal::new();
 
     *TX_PORTAL.lock().unwrap() = Some(portal.tx_portal.clone());
@@ -65,10 +114,20 @@ async fn main() -> Result<(), Box<dyn Error>> {
     let mut link_acceptor = LinkAcceptor::new(args.link, portal.tx_portal.clone()).await?;
     v.push(tokio::spawn(async move {link_acceptor.run().await;}));
     info!("Starting up telnet acceptor on {}...", args.telnet);
-    let mut telnet_acceptor = TelnetAcceptor::new(args.telnet, portal.tx_portal.clone()).await?;
+    let mut telnet_acceptor = TelnetAcceptor::new(args.telnet, portal.tx_portal.clone(), tls_acceptor.clone(), false, telnet_options).await?;
     v.push(tokio::spawn(async move {telnet_acceptor.run().await;}));
-
-    v.push(tokio::spawn(run_warp(args.web, args.pem, args.key)));
+    if let Some(addr) = args.telnet_tls {
+        match tls_acceptor.clone() {
+            Some(acceptor) => {
+                info!("Starting up telnet TLS acceptor on {}...", addr);
+                let mut telnet_tls_acceptor = TelnetAcceptor::new(addr, portal.tx_portal.clone(), Some(acceptor), true, telnet_tls_options).await?;
+                v.push(tokio::spawn(async move {telnet_tls_acceptor.run().await;}));
+            },
+            None => error!("--telnet-tls needs --pem and --key, not starting telnet TLS acceptor.")
+        }
+    }
+
+    v.push(tokio::spawn(run_warp(args.web, args.pem, args.key, tls_acceptor)));
 
     info!("Starting up portal...");
     v.push(tokio::spawn(async move {portal.run().await;}));
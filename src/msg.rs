@@ -1,24 +1,35 @@
 use std::collections::HashMap;
 use crate::protocols::link::protocol::LinkStub;
-use crate::protocols::{ProtocolCapabilities, ProtocolLink, MudData};
+use crate::protocols::{ConnectionStats, ProtocolCapabilities, ProtocolLink, MudData};
 use serde_json::Value as JsonValue;
 
 #[derive(Debug)]
 pub enum Msg2MudProtocol {
     Disconnect,
-    Data(Vec<MudData>)
+    Data(Vec<MudData>),
+    RequestStats,
+    // A named per-client setting from the game, such as prompt_terminator.
+    SetOption(String, JsonValue)
 }
 
+// Capabilities updates are rare next to data, so they're not worth boxing.
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug)]
 pub enum Msg2PortalFromClient {
     Capabilities(ProtocolCapabilities),
-    Data(Vec<MudData>)
+    Data(Vec<MudData>),
+    // Names of OOB commands the client had no way to receive.
+    OobDropped(Vec<String>),
+    Stats(ConnectionStats)
 }
 
 #[derive(Debug)]
 pub enum Msg2PortalFromLink {
     ClientMessage(usize, Vec<MudData>),
-    ClientDisconnected(usize, String)
+    ClientDisconnected(usize, String),
+    ClientRequestStats(usize),
+    ClientOption(usize, String, JsonValue),
+    Mssp(HashMap<String, JsonValue>)
 }
 
 #[derive(Debug)]
@@ -41,5 +52,7 @@ pub enum Msg2Link {
     ClientDisconnected(usize, String),
     ClientCapabilities(usize, ProtocolCapabilities),
     ClientData(usize, Vec<MudData>),
+    ClientOobDropped(usize, Vec<String>),
+    ClientStats(usize, ConnectionStats),
     ClientList(HashMap<usize, ProtocolLink>),
 }
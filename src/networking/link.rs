@@ -25,6 +25,8 @@ use tokio_util::codec::Framed;
 use crate::msg::{Msg2Link, Msg2Portal};
 use crate::protocols::link::protocol::{LinkProtocol, LinkStub};
 use crate::networking::CONNECTION_ID_COUNTER;
+use crate::networking::proxy::resolve_client;
+use crate::CONFIG;
 
 use crate::util::{ClientHelloStatus, check_tls_client_hello, check_http_request, HttpRequestStatus, generate_id};
 
@@ -82,7 +84,9 @@ impl LinkHandler {
         }
     }
 
-    pub async fn run(&mut self, stream: TcpStream) -> Result<(), Box<dyn Error>> {
+    pub async fn run(&mut self, mut stream: TcpStream) -> Result<(), Box<dyn Error>> {
+        let proxy = CONFIG.lock().unwrap().proxy.link;
+        self.addr = resolve_client(&mut stream, self.addr, proxy).await?;
 
         let ws_stream = accept_async(stream).await?;
         let conn_id = CONNECTION_ID_COUNTER.fetch_add(1, Ordering::SeqCst);
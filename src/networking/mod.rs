@@ -1,7 +1,9 @@
 use std::sync::atomic::AtomicUsize;
 
 pub mod link;
+pub mod proxy;
 pub mod telnet;
+pub mod tls;
 pub mod web;
 
 pub static CONNECTION_ID_COUNTER: AtomicUsize = AtomicUsize::new(0);
\ No newline at end of file
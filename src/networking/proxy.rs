@@ -0,0 +1,264 @@
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    time::timeout
+};
+
+use crate::CONFIG;
+
+// HAProxy PROXY protocol, as described in
+// https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt
+//
+// v1 is a single text line: PROXY TCP4 <src> <dst> <srcport> <dstport>\r\n
+// v2 is binary: a 12 byte signature, version/command, family, a 16-bit length and then the
+// addresses followed by optional TLVs, which we don't need.
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+// The longest legal v1 line, CRLF included.
+const V1_MAX_LEN: usize = 107;
+
+const V2_CMD_LOCAL: u8 = 0x0;
+const V2_CMD_PROXY: u8 = 0x1;
+const V2_AF_INET: u8 = 0x1;
+const V2_AF_INET6: u8 = 0x2;
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+// Works out the real client address of a freshly accepted connection. enabled is the listener's
+// switch from the [proxy] config. If it's on and the peer is a trusted proxy, the header is
+// mandatory and is consumed from the stream, leaving only the client's own bytes behind.
+pub async fn resolve_client<S: AsyncRead + Unpin>(stream: &mut S, peer: SocketAddr, enabled: bool) -> io::Result<SocketAddr> {
+    let wait = {
+        let config = CONFIG.lock().unwrap();
+        if !enabled || !config.proxy.trusts(peer.ip()) {
+            return Ok(peer);
+        }
+        Duration::from_millis(config.proxy.timeout_ms)
+    };
+
+    match timeout(wait, read_header(stream)).await {
+        Ok(Ok(Some(addr))) => Ok(addr),
+        Ok(Ok(None)) => Ok(peer),
+        Ok(Err(e)) => Err(e),
+        Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "timed out waiting for PROXY header"))
+    }
+}
+
+// Reads exactly one v1 or v2 header. Returns None for headers that carry no client address
+// (v1 UNKNOWN, v2 LOCAL health checks, or non-IP families), in which case the peer address stands.
+// The shortest v1 header is 15 bytes, so reading 12 up front is safe for both versions.
+pub async fn read_header<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    let mut head = [0u8; 12];
+    stream.read_exact(&mut head).await?;
+
+    if head == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if head.starts_with(b"PROXY ") {
+        read_v1(stream, &head).await
+    } else {
+        Err(invalid("connection from trusted proxy did not start with a PROXY header"))
+    }
+}
+
+async fn read_v1<S: AsyncRead + Unpin>(stream: &mut S, head: &[u8]) -> io::Result<Option<SocketAddr>> {
+    // Byte at a time so that nothing past the CRLF is taken off the socket.
+    let mut line = head.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err(invalid("PROXY v1 header too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+    line.truncate(line.len() - 2);
+    parse_v1(&line)
+}
+
+fn parse_v1(line: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let line = std::str::from_utf8(line).map_err(|_| invalid("PROXY v1 header is not ASCII"))?;
+    let parts: Vec<&str> = line.split(' ').collect();
+
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", src, _dst, src_port, _dst_port] => {
+            let ip: IpAddr = src.parse().map_err(|_| invalid("bad source address in PROXY v1 header"))?;
+            let port: u16 = src_port.parse().map_err(|_| invalid("bad source port in PROXY v1 header"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        },
+        _ => Err(invalid("malformed PROXY v1 header"))
+    }
+}
+
+async fn read_v2<S: AsyncRead + Unpin>(stream: &mut S) -> io::Result<Option<SocketAddr>> {
+    let mut hdr = [0u8; 4];
+    stream.read_exact(&mut hdr).await?;
+
+    let version = hdr[0] >> 4;
+    let command = hdr[0] & 0x0F;
+    let family = hdr[1] >> 4;
+    let len = u16::from_be_bytes([hdr[2], hdr[3]]) as usize;
+
+    if version != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+
+    // The whole body is always read, even when unused, so the stream is left at the client data.
+    let mut body = vec![0u8; len];
+    stream.read_exact(&mut body).await?;
+
+    match command {
+        V2_CMD_LOCAL => Ok(None),
+        V2_CMD_PROXY => parse_v2_addr(family, &body),
+        _ => Err(invalid("unknown PROXY v2 command"))
+    }
+}
+
+fn parse_v2_addr(family: u8, body: &[u8]) -> io::Result<Option<SocketAddr>> {
+    match family {
+        V2_AF_INET if body.len() >= 12 => {
+            let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            let port = u16::from_be_bytes([body[8], body[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        },
+        V2_AF_INET6 if body.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&body[0..16]);
+            let port = u16::from_be_bytes([body[32], body[33]]);
+            Ok(Some(SocketAddr::new(IpAddr::V6(Ipv6Addr::from(octets)), port)))
+        },
+        // An IP family without room for its addresses is a broken header, not a missing one.
+        V2_AF_INET | V2_AF_INET6 => Err(invalid("PROXY v2 address block too short")),
+        _ => Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn v2(command: u8, family: u8, body: &[u8]) -> Vec<u8> {
+        let mut out = V2_SIGNATURE.to_vec();
+        out.push(0x20 | command);
+        // Stream transport.
+        out.push((family << 4) | 0x1);
+        out.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        out.extend_from_slice(body);
+        out
+    }
+
+    // Reads a header off the front of data and returns the address along with what's left.
+    async fn read(data: &[u8]) -> io::Result<(Option<SocketAddr>, Vec<u8>)> {
+        let mut stream = data;
+        let addr = read_header(&mut stream).await?;
+        Ok((addr, stream.to_vec()))
+    }
+
+    #[tokio::test]
+    async fn v1() {
+        let (addr, rest) = read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 4000\r\nlook").await.unwrap();
+        assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"look");
+
+        let (addr, _) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 4000\r\n").await.unwrap();
+        assert_eq!(addr, Some("[2001:db8::1]:56324".parse().unwrap()));
+
+        let (addr, rest) = read(b"PROXY UNKNOWN\r\nlook").await.unwrap();
+        assert_eq!(addr, None);
+        assert_eq!(rest, b"look");
+    }
+
+    #[tokio::test]
+    async fn v1_errors() {
+        let long = format!("PROXY TCP4 {}\r\n", "1".repeat(V1_MAX_LEN));
+        assert!(read(long.as_bytes()).await.is_err());
+        assert!(read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n").await.is_err());
+        assert!(read(b"PROXY TCP4 192.0.2.300 198.51.100.1 56324 4000\r\n").await.is_err());
+        assert!(read(b"PROXY TCP4 192.0.2.1 198.51.100.1 99999 4000\r\n").await.is_err());
+        // The connection closed before the line ended.
+        assert!(read(b"PROXY TCP4 192.0.2.1").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn v2_proxy() {
+        let mut body = vec![192, 0, 2, 1, 198, 51, 100, 1];
+        body.extend_from_slice(&56324u16.to_be_bytes());
+        body.extend_from_slice(&4000u16.to_be_bytes());
+        let mut data = v2(V2_CMD_PROXY, V2_AF_INET, &body);
+        data.extend_from_slice(b"look");
+        let (addr, rest) = read(&data).await.unwrap();
+        assert_eq!(addr, Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"look");
+
+        let src: Ipv6Addr = "2001:db8::1".parse().unwrap();
+        let mut body = src.octets().to_vec();
+        body.extend_from_slice(&[0; 16]);
+        body.extend_from_slice(&56324u16.to_be_bytes());
+        body.extend_from_slice(&4000u16.to_be_bytes());
+        // A TLV after the addresses is skipped.
+        body.extend_from_slice(&[0x04, 0x00, 0x01, 0xff]);
+        let (addr, rest) = read(&v2(V2_CMD_PROXY, V2_AF_INET6, &body)).await.unwrap();
+        assert_eq!(addr, Some("[2001:db8::1]:56324".parse().unwrap()));
+        assert!(rest.is_empty());
+    }
+
+    #[tokio::test]
+    async fn v2_local() {
+        let mut data = v2(V2_CMD_LOCAL, 0, &[]);
+        data.extend_from_slice(b"look");
+        let (addr, rest) = read(&data).await.unwrap();
+        assert_eq!(addr, None);
+        assert_eq!(rest, b"look");
+    }
+
+    #[tokio::test]
+    async fn v2_errors() {
+        // Too short for two IPv4 addresses and ports.
+        assert!(read(&v2(V2_CMD_PROXY, V2_AF_INET, &[192, 0, 2, 1, 198, 51])).await.is_err());
+        assert!(read(&v2(V2_CMD_PROXY, V2_AF_INET6, &[0; 20])).await.is_err());
+        // The length promises more than the connection sent.
+        let mut data = v2(V2_CMD_PROXY, V2_AF_INET, &[0; 12]);
+        data.truncate(data.len() - 4);
+        assert!(read(&data).await.is_err());
+        // Version 1 in the binary format, and an unknown command.
+        let mut data = v2(V2_CMD_PROXY, V2_AF_INET, &[0; 12]);
+        data[12] = 0x11;
+        assert!(read(&data).await.is_err());
+        assert!(read(&v2(0x2, V2_AF_INET, &[0; 12])).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn bad_signature() {
+        assert!(read(b"GET / HTTP/1.1\r\n\r\n").await.is_err());
+        let mut data = v2(V2_CMD_LOCAL, 0, &[]);
+        data[11] = b'X';
+        assert!(read(&data).await.is_err());
+    }
+
+    // A trusted proxy that sends a bad header gets an error, which closes the connection, rather
+    // than the proxy's own address standing in for the client's.
+    #[tokio::test]
+    async fn bad_header_is_not_the_peer() {
+        CONFIG.lock().unwrap().proxy.trusted = vec!["127.0.0.0/8".parse().unwrap()];
+        let peer: SocketAddr = "127.0.0.1:40000".parse().unwrap();
+
+        let mut stream: &[u8] = b"look\r\n and more than twelve bytes";
+        assert!(resolve_client(&mut stream, peer, true).await.is_err());
+        let data = v2(V2_CMD_PROXY, V2_AF_INET, &[192, 0, 2, 1]);
+        assert!(resolve_client(&mut data.as_slice(), peer, true).await.is_err());
+
+        let mut stream: &[u8] = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 4000\r\n";
+        assert_eq!(resolve_client(&mut stream, peer, true).await.unwrap(), "192.0.2.1:56324".parse().unwrap());
+        // Untrusted peers and listeners without PROXY support are taken as they are.
+        let mut stream: &[u8] = b"look\r\n";
+        let outsider: SocketAddr = "192.0.2.9:40000".parse().unwrap();
+        assert_eq!(resolve_client(&mut stream, outsider, true).await.unwrap(), outsider);
+        assert_eq!(resolve_client(&mut stream, peer, false).await.unwrap(), peer);
+    }
+}
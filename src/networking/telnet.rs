@@ -15,28 +15,46 @@ use tokio::{
 
 use trust_dns_resolver::TokioAsyncResolver;
 
-use tokio::io::{AsyncRead, AsyncWrite, AsyncReadExt};
-use tokio::time::timeout;
+use tokio::time::{sleep, timeout_at, Instant};
 use tokio_util::codec::Framed;
 use crate::msg::Msg2Portal;
 use crate::protocols::telnet::codec::TelnetCodec;
-use crate::protocols::telnet::protocol::TelnetProtocol;
+use crate::protocols::telnet::options::TelnetOptionRegistry;
+use crate::protocols::telnet::protocol::{TelnetClientInfo, TelnetProtocol};
 use crate::networking::CONNECTION_ID_COUNTER;
+use crate::CONFIG;
+use crate::config::KeepaliveMode;
 
+use socket2::{SockRef, TcpKeepalive};
+use tracing::warn;
+
+use crate::networking::proxy::resolve_client;
+use crate::networking::tls::{self, PrefixedStream, TelnetStream};
 use crate::util::{ClientHelloStatus, check_tls_client_hello};
 
+use tokio_rustls::TlsAcceptor;
+
 pub struct TelnetAcceptor {
     listener: TcpListener,
-    tx_portal: Sender<Msg2Portal>
+    tx_portal: Sender<Msg2Portal>,
+    // Used for STARTTLS and ClientHello sniffing on a plain port, or for every connection when
+    // implicit_tls is set.
+    tls: Option<TlsAcceptor>,
+    implicit_tls: bool,
+    // The telnet options this listener speaks.
+    options: Arc<TelnetOptionRegistry>
 }
 
 impl TelnetAcceptor {
-    pub async fn new(addr: SocketAddr, tx_portal: Sender<Msg2Portal>) -> Result<Self, Box<dyn Error>> {
+    pub async fn new(addr: SocketAddr, tx_portal: Sender<Msg2Portal>, tls: Option<TlsAcceptor>, implicit_tls: bool, options: TelnetOptionRegistry) -> Result<Self, Box<dyn Error>> {
         let listener = TcpListener::bind(addr).await?;
 
         Ok(TelnetAcceptor {
             listener,
-            tx_portal
+            tx_portal,
+            tls,
+            implicit_tls,
+            options: Arc::new(options)
         })
     }
 
@@ -44,7 +62,7 @@ impl TelnetAcceptor {
         loop {
             match self.listener.accept().await {
                 Ok((stream, addr)) => {
-                    let mut handler = TelnetHandler::new(addr, self.tx_portal.clone());
+                    let mut handler = TelnetHandler::new(addr, self.tx_portal.clone(), self.tls.clone(), self.implicit_tls, self.options.clone());
                     tokio::spawn(async move {
                         match handler.run(stream).await {
                             Ok(()) => {},
@@ -65,39 +83,94 @@ impl TelnetAcceptor {
 pub struct TelnetHandler {
     addr: SocketAddr,
     tx_portal: Sender<Msg2Portal>,
-    hostnames: Vec<String>
+    hostnames: Vec<String>,
+    tls: Option<TlsAcceptor>,
+    implicit_tls: bool,
+    options: Arc<TelnetOptionRegistry>
 }
 
 impl TelnetHandler {
 
-    pub fn new(addr: SocketAddr, tx_portal: Sender<Msg2Portal>) -> Self {
+    pub fn new(addr: SocketAddr, tx_portal: Sender<Msg2Portal>, tls: Option<TlsAcceptor>, implicit_tls: bool, options: Arc<TelnetOptionRegistry>) -> Self {
         Self {
             addr,
             tx_portal,
-            hostnames: Vec::new()
+            hostnames: Vec::new(),
+            tls,
+            implicit_tls,
+            options
         }
     }
 
-    pub async fn run(&mut self, stream: TcpStream) -> Result<(), Box<dyn Error>> {
+    pub async fn run(&mut self, mut stream: TcpStream) -> Result<(), Box<dyn Error>> {
+        let proxy = CONFIG.lock().unwrap().proxy.telnet;
+        self.addr = resolve_client(&mut stream, self.addr, proxy).await?;
+
+        let telnet = CONFIG.lock().unwrap().telnet.clone();
+        if telnet.keepalive == KeepaliveMode::Tcp && telnet.keepalive_secs > 0 {
+            let wait = Duration::from_secs(telnet.keepalive_secs);
+            let keepalive = TcpKeepalive::new().with_time(wait).with_interval(wait);
+            if let Err(e) = SockRef::from(&stream).set_tcp_keepalive(&keepalive) {
+                warn!("Error enabling TCP keepalive: {}", e);
+            }
+        }
+
         let resolver = TokioAsyncResolver::tokio_from_system_conf()?;
 
         if let Ok(response) = resolver.reverse_lookup(self.addr.ip()).await {
             self.hostnames = response.iter().map(|x| x.to_string()).collect();
         }
 
-        self.handle_telnet_connection(stream, false).await?;
+        let stream = match &self.tls {
+            Some(acceptor) if self.implicit_tls || self.sniff_tls(&stream).await => {
+                TelnetStream::Tls(Box::new(tls::accept(acceptor, PrefixedStream::new(stream)).await?))
+            },
+            _ => TelnetStream::Plain(PrefixedStream::new(stream))
+        };
+
+        self.handle_telnet_connection(stream).await?;
 
         Ok(())
     }
 
-    pub async fn handle_telnet_connection<S>(&mut self, mut socket: S,tls_engaged: bool) -> Result<(), Box<dyn std::error::Error>>
-        where
-            S: AsyncRead + AsyncReadExt + AsyncWrite + Unpin + Send + Sync + 'static,
-    {
-        let conn_id = CONNECTION_ID_COUNTER.fetch_add(1, Ordering::SeqCst);
-        let telnet_codec = Framed::new(socket, TelnetCodec::new(8192));
+    // On a plain port, a TLS client speaks first with a ClientHello while a telnet client
+    // usually waits for us. Peek briefly to tell them apart without consuming anything. The
+    // record header can arrive in pieces, so keep peeking until it's all there or time is up.
+    async fn sniff_tls(&self, stream: &TcpStream) -> bool {
+        let deadline = Instant::now() + Duration::from_millis(CONFIG.lock().unwrap().telnet.tls_sniff_timeout_ms);
+        let mut buf = [0u8; 5];
+        loop {
+            let n = match timeout_at(deadline, stream.peek(&mut buf)).await {
+                Ok(Ok(n)) if n > 0 => n,
+                _ => return false
+            };
+            match check_tls_client_hello(&buf[..n]) {
+                ClientHelloStatus::Complete => return true,
+                ClientHelloStatus::Invalid => return false,
+                // peek returns straight away while anything is buffered, so wait for more.
+                ClientHelloStatus::Partial if Instant::now() < deadline => sleep(Duration::from_millis(10)).await,
+                ClientHelloStatus::Partial => return false
+            }
+        }
+    }
 
-        let mut tel_prot = TelnetProtocol::new(conn_id, telnet_codec, self.addr.clone(), self.hostnames.clone(), tls_engaged, self.tx_portal.clone());
+    pub async fn handle_telnet_connection(&mut self, socket: TelnetStream) -> Result<(), Box<dyn std::error::Error>> {
+        let conn_id = CONNECTION_ID_COUNTER.fetch_add(1, Ordering::SeqCst);
+        let tls_engaged = socket.is_tls();
+        let compression_level = CONFIG.lock().unwrap().telnet.compression_level;
+        let telnet_codec = Framed::new(socket, TelnetCodec::new(compression_level));
+
+        // STARTTLS is only on offer if we have a certificate and aren't encrypted already.
+        let starttls = if tls_engaged { None } else { self.tls.clone() };
+
+        let client = TelnetClientInfo {
+            addr: self.addr,
+            hostnames: self.hostnames.clone(),
+            tls: tls_engaged,
+            starttls,
+            options: self.options.clone()
+        };
+        let mut tel_prot = TelnetProtocol::new(conn_id, telnet_codec, client, self.tx_portal.clone());
 
         tel_prot.run().await;
 
@@ -0,0 +1,169 @@
+use std::{
+    error::Error,
+    fs::File,
+    io::{self, BufReader},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration
+};
+
+use async_trait::async_trait;
+
+use bytes::{Buf, BytesMut};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+    time::timeout
+};
+
+use tokio_rustls::{
+    rustls::ServerConfig,
+    server::TlsStream,
+    TlsAcceptor
+};
+
+use crate::CONFIG;
+
+pub fn load_tls_acceptor(pem: &str, key: &str) -> Result<TlsAcceptor, Box<dyn Error>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(pem)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key)?))?
+        .ok_or("no private key found in key file")?;
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+// Runs the server side of a TLS handshake, giving up after tls.handshake_timeout_ms so a client
+// that connects and goes quiet can't hold the connection open.
+pub async fn accept<IO>(acceptor: &TlsAcceptor, stream: IO) -> io::Result<TlsStream<IO>> where IO: AsyncRead + AsyncWrite + Unpin {
+    let wait = Duration::from_millis(CONFIG.lock().unwrap().tls.handshake_timeout_ms);
+    match timeout(wait, acceptor.accept(stream)).await {
+        Ok(result) => result,
+        Err(_) => Err(io::Error::new(io::ErrorKind::TimedOut, "TLS handshake timed out"))
+    }
+}
+
+// A TcpStream with some bytes that have to be read before anything new from the socket.
+// STARTTLS needs this: the client's ClientHello may already be sitting in our telnet buffers
+// by the time we see its IAC SB START_TLS FOLLOWS IAC SE.
+pub struct PrefixedStream {
+    prefix: BytesMut,
+    inner: TcpStream
+}
+
+impl PrefixedStream {
+    pub fn new(inner: TcpStream) -> Self {
+        Self {
+            prefix: BytesMut::new(),
+            inner
+        }
+    }
+}
+
+impl AsyncRead for PrefixedStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        if !self.prefix.is_empty() {
+            let n = self.prefix.len().min(buf.remaining());
+            buf.put_slice(&self.prefix[..n]);
+            self.prefix.advance(n);
+            return Poll::Ready(Ok(()));
+        }
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for PrefixedStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+// The socket under a telnet connection. It starts out plain or TLS, and a plain one can be
+// upgraded in place by STARTTLS without the TelnetProtocol on top having to know.
+pub enum TelnetStream {
+    Plain(PrefixedStream),
+    Tls(Box<TlsStream<PrefixedStream>>),
+    // Only seen for the duration of a failed STARTTLS handshake.
+    Closed
+}
+
+impl TelnetStream {
+    pub fn is_tls(&self) -> bool {
+        matches!(self, TelnetStream::Tls(_))
+    }
+}
+
+#[async_trait]
+pub trait StartTls {
+    // Runs the server side of a TLS handshake over the existing connection. pending holds
+    // bytes already read off the socket that belong to the handshake.
+    async fn start_tls(&mut self, acceptor: &TlsAcceptor, pending: BytesMut) -> io::Result<()>;
+}
+
+#[async_trait]
+impl StartTls for TelnetStream {
+    async fn start_tls(&mut self, acceptor: &TlsAcceptor, pending: BytesMut) -> io::Result<()> {
+        match std::mem::replace(self, TelnetStream::Closed) {
+            TelnetStream::Plain(mut stream) => {
+                stream.prefix.unsplit(pending);
+                let tls = accept(acceptor, stream).await?;
+                *self = TelnetStream::Tls(Box::new(tls));
+                Ok(())
+            },
+            other => {
+                *self = other;
+                Err(io::Error::other("connection is already using TLS"))
+            }
+        }
+    }
+}
+
+impl AsyncRead for TelnetStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            TelnetStream::Plain(s) => Pin::new(s).poll_read(cx, buf),
+            TelnetStream::Tls(s) => Pin::new(s).poll_read(cx, buf),
+            TelnetStream::Closed => Poll::Ready(Ok(()))
+        }
+    }
+}
+
+impl AsyncWrite for TelnetStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            TelnetStream::Plain(s) => Pin::new(s).poll_write(cx, buf),
+            TelnetStream::Tls(s) => Pin::new(s).poll_write(cx, buf),
+            TelnetStream::Closed => Poll::Ready(Err(io::ErrorKind::NotConnected.into()))
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            TelnetStream::Plain(s) => Pin::new(s).poll_flush(cx),
+            TelnetStream::Tls(s) => Pin::new(s).poll_flush(cx),
+            TelnetStream::Closed => Poll::Ready(Ok(()))
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            TelnetStream::Plain(s) => Pin::new(s).poll_shutdown(cx),
+            TelnetStream::Tls(s) => Pin::new(s).poll_shutdown(cx),
+            TelnetStream::Closed => Poll::Ready(Ok(()))
+        }
+    }
+}
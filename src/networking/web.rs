@@ -3,13 +3,24 @@ use std::net::SocketAddr;
 use std::sync::atomic::Ordering;
 use once_cell::sync::Lazy;
 
+use hyper::{server::conn::Http, service::{service_fn, Service}};
+use tokio::net::TcpListener;
+use tokio_rustls::TlsAcceptor;
+use tracing::{error, warn};
+
 use crate::{
     protocols::websocket::protocol::WebsocketProtocol,
-    networking::CONNECTION_ID_COUNTER,
+    networking::{CONNECTION_ID_COUNTER, proxy::resolve_client, tls},
     IS_TLS_ENABLED,
+    CONFIG,
     util::resolve_hostname
 };
 
+// The client address as read from a PROXY header. warp only knows the socket's peer address,
+// so when we run our own accept loop the real one rides along as a request extension.
+#[derive(Clone, Copy)]
+struct ClientAddr(SocketAddr);
+
 static TERA: Lazy<tera::Tera> = Lazy::new(|| {
     let mut tera = match tera::Tera::new("webroot/**/*.html") {
         Ok(t) => t,
@@ -43,10 +54,15 @@ async fn handle_websocket(ws: warp::ws::WebSocket, addr: Option<SocketAddr>) {
 
 }
 
-pub async fn run_warp(addr: SocketAddr, pem: Option<String>, key: Option<String>) {
+pub async fn run_warp(addr: SocketAddr, pem: Option<String>, key: Option<String>, tls: Option<TlsAcceptor>) {
+    // Get the remote address, preferring the one a trusted proxy told us about.
+    let remote = warp::ext::optional::<ClientAddr>()
+        .and(warp::addr::remote())
+        .map(|client: Option<ClientAddr>, remote: Option<SocketAddr>| client.map(|c| c.0).or(remote));
+
     // WebSocket route
     let ws_route = warp::path("ws")
-        .and(warp::addr::remote()) // Get the remote address
+        .and(remote)
         .and(warp::ws())
         .map(|remote_addr: Option<SocketAddr>, ws: warp::ws::Ws| {
             // You can now access the remote address inside this closure
@@ -82,6 +98,60 @@ pub async fn run_warp(addr: SocketAddr, pem: Option<String>, key: Option<String>
         .or(wclient)
         .with(log);
 
+    if CONFIG.lock().unwrap().proxy.web {
+        // warp's own listeners can't see a PROXY header, so accept connections ourselves, strip
+        // the header off and hand the rest of the stream to hyper.
+        let listener = match TcpListener::bind(addr).await {
+            Ok(l) => l,
+            Err(e) => {
+                error!("Error binding web listener: {}", e);
+                return;
+            }
+        };
+        let svc = warp::service(routes);
+
+        loop {
+            let (mut stream, peer) = match listener.accept().await {
+                Ok(v) => v,
+                Err(e) => {
+                    warn!("Error accepting connection: {}", e);
+                    continue;
+                }
+            };
+            let svc = svc.clone();
+            let tls = tls.clone();
+
+            tokio::spawn(async move {
+                let client = match resolve_client(&mut stream, peer, true).await {
+                    Ok(client) => client,
+                    Err(e) => {
+                        warn!("Error accepting web connection: {}", e);
+                        return;
+                    }
+                };
+                let svc = service_fn(move |mut req| {
+                    req.extensions_mut().insert(ClientAddr(client));
+                    svc.clone().call(req)
+                });
+
+                let http = Http::new();
+                let result = match tls {
+                    Some(acceptor) => match tls::accept(&acceptor, stream).await {
+                        Ok(stream) => http.serve_connection(stream, svc).with_upgrades().await,
+                        Err(e) => {
+                            warn!("Error accepting web connection: {}", e);
+                            return;
+                        }
+                    },
+                    None => http.serve_connection(stream, svc).with_upgrades().await
+                };
+                if let Err(e) = result {
+                    warn!("Error serving web connection: {}", e);
+                }
+            });
+        }
+    }
+
     let warp = warp::serve(routes);
 
     if *IS_TLS_ENABLED.lock().unwrap() {
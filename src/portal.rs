@@ -25,6 +25,7 @@ use crate::{
 };
 use crate::msg::{Msg2Link, Msg2MudProtocol, Msg2Portal, Msg2PortalFromClient, Msg2PortalFromLink};
 use crate::protocols::{ProtocolLink, MudData};
+use crate::MSSP;
 
 
 pub struct Portal {
@@ -79,6 +80,12 @@ impl Portal {
         }
     }
 
+    // Crawlers are answered by the telnet protocols straight from the MSSP cache, so the
+    // player count there has to follow the client list.
+    fn update_mssp_players(&self) {
+        MSSP.lock().unwrap().players = self.clients.len();
+    }
+
     async fn handle_interval_timer(&mut self) {
         if self.link.is_none() {
             let _ = self.message_all_clients("Portal awaiting connection from game server...\r\n").await;
@@ -102,6 +109,16 @@ impl Portal {
                                 let _ = link.tx_link.send(Msg2Link::ClientData(conn_id, data)).await;
                             }
                         }
+                        Msg2PortalFromClient::OobDropped(cmds) => {
+                            if let Some(link) = self.link.as_mut() {
+                                let _ = link.tx_link.send(Msg2Link::ClientOobDropped(conn_id, cmds)).await;
+                            }
+                        }
+                        Msg2PortalFromClient::Stats(stats) => {
+                            if let Some(link) = self.link.as_mut() {
+                                let _ = link.tx_link.send(Msg2Link::ClientStats(conn_id, stats)).await;
+                            }
+                        }
                     }
                 }
             }
@@ -116,17 +133,35 @@ impl Portal {
                         if let Some(client) = self.clients.remove(&client_id) {
                             let _ = client.tx_protocol.send(Msg2MudProtocol::Disconnect).await;
                         }
+                        self.update_mssp_players();
+                    }
+                    Msg2PortalFromLink::ClientRequestStats(client_id) => {
+                        if let Some(client) = self.clients.get_mut(&client_id) {
+                            let _ = client.tx_protocol.send(Msg2MudProtocol::RequestStats).await;
+                        }
+                    }
+                    Msg2PortalFromLink::ClientOption(client_id, name, value) => {
+                        if let Some(client) = self.clients.get_mut(&client_id) {
+                            let _ = client.tx_protocol.send(Msg2MudProtocol::SetOption(name, value)).await;
+                        }
+                    }
+                    Msg2PortalFromLink::Mssp(data) => {
+                        MSSP.lock().unwrap().data = data;
                     }
                 }
             },
             Msg2Portal::ClientDisconnected(conn_id, reason) => {
-                if let Some(link) = self.link.as_mut() {
-                    let _ = link.tx_link.send(Msg2Link::ClientDisconnected(conn_id, reason)).await;
+                // Connections that never reached the game, such as MSSP crawlers, leave quietly.
+                if self.clients.remove(&conn_id).is_some() {
+                    if let Some(link) = self.link.as_mut() {
+                        let _ = link.tx_link.send(Msg2Link::ClientDisconnected(conn_id, reason)).await;
+                    }
                 }
-                let _ = self.clients.remove(&conn_id);
+                self.update_mssp_players();
             },
             Msg2Portal::ClientConnected(stub) => {
                 self.clients.insert(stub.conn_id, stub.clone());
+                self.update_mssp_players();
                 if let Some(link) = self.link.as_mut() {
                     let _ = link.tx_link.send(Msg2Link::ClientReady(stub)).await;
                 } else {
@@ -159,4 +194,39 @@ impl Portal {
             }
         }
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn with_link() -> (Portal, Receiver<Msg2Link>) {
+        let mut portal = Portal::new();
+        let (tx_link, mut rx_link) = channel(10);
+        let stub = LinkStub { conn_id: 0, addr: "127.0.0.1:7000".parse().unwrap(), tls: false, tx_link };
+        portal.handle_portal_message(Msg2Portal::LinkConnected(stub)).await;
+        assert!(matches!(rx_link.try_recv(), Ok(Msg2Link::ClientList(_))));
+        (portal, rx_link)
+    }
+
+    // A crawler is answered by the portal and never announced, so its hanging up must not be
+    // either.
+    #[tokio::test]
+    async fn crawler_disconnect_stays_hidden() {
+        let (mut portal, mut rx_link) = with_link().await;
+        portal.handle_portal_message(Msg2Portal::ClientDisconnected(5, "connection closed".to_string())).await;
+        assert!(rx_link.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn client_disconnect_reaches_game() {
+        let (mut portal, mut rx_link) = with_link().await;
+        let (tx_protocol, _rx_protocol) = channel(10);
+        let client = ProtocolLink { conn_id: 6, capabilities: Default::default(), tx_protocol };
+        portal.handle_portal_message(Msg2Portal::ClientConnected(client)).await;
+        assert!(matches!(rx_link.try_recv(), Ok(Msg2Link::ClientReady(_))));
+
+        portal.handle_portal_message(Msg2Portal::ClientDisconnected(6, "connection closed".to_string())).await;
+        assert!(matches!(rx_link.try_recv(), Ok(Msg2Link::ClientDisconnected(6, _))));
+        assert!(rx_link.try_recv().is_err());
+    }
+}
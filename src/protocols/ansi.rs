@@ -0,0 +1,239 @@
+use crate::protocols::Color;
+
+// Rewrites ANSI SGR sequences (ESC [ ... m) so they don't exceed what a client can display.
+// Games write their richest colors and the portal steps them down per client:
+// truecolor becomes the nearest xterm256 index, xterm256 the nearest of the 16 standard colors,
+// and for NoColor every SGR sequence is removed. Other escape sequences are left alone.
+
+// The usual xterm RGB values for the 16 standard colors.
+static ANSI16_RGB: [(u8, u8, u8); 16] = [
+    (0, 0, 0), (205, 0, 0), (0, 205, 0), (205, 205, 0),
+    (0, 0, 238), (205, 0, 205), (0, 205, 205), (229, 229, 229),
+    (127, 127, 127), (255, 0, 0), (0, 255, 0), (255, 255, 0),
+    (92, 92, 255), (255, 0, 255), (0, 255, 255), (255, 255, 255)
+];
+
+// Channel values of the 6x6x6 cube in the xterm256 palette.
+static CUBE_STEPS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+
+fn distance(a: (u8, u8, u8), b: (u8, u8, u8)) -> u32 {
+    let dr = a.0 as i32 - b.0 as i32;
+    let dg = a.1 as i32 - b.1 as i32;
+    let db = a.2 as i32 - b.2 as i32;
+    (dr * dr + dg * dg + db * db) as u32
+}
+
+pub fn xterm_to_rgb(idx: u8) -> (u8, u8, u8) {
+    match idx {
+        0..=15 => ANSI16_RGB[idx as usize],
+        16..=231 => {
+            let i = idx - 16;
+            (CUBE_STEPS[(i / 36) as usize], CUBE_STEPS[((i / 6) % 6) as usize], CUBE_STEPS[(i % 6) as usize])
+        },
+        _ => {
+            let level = 8 + (idx - 232) * 10;
+            (level, level, level)
+        }
+    }
+}
+
+fn nearest_step(v: u8) -> u8 {
+    (0..6).min_by_key(|&i| (CUBE_STEPS[i] as i32 - v as i32).abs()).unwrap_or(0) as u8
+}
+
+// Picks the closest of the cube color and the grayscale ramp entry. The 16 standard colors are
+// left out since terminals disagree about what they look like.
+pub fn rgb_to_xterm(rgb: (u8, u8, u8)) -> u8 {
+    let (r, g, b) = (nearest_step(rgb.0), nearest_step(rgb.1), nearest_step(rgb.2));
+    let cube = 16 + 36 * r + 6 * g + b;
+
+    let avg = (rgb.0 as u32 + rgb.1 as u32 + rgb.2 as u32) / 3;
+    let gray = if avg < 8 { 232 } else { (232 + ((avg - 8) / 10).min(23)) as u8 };
+
+    if distance(rgb, xterm_to_rgb(gray)) < distance(rgb, xterm_to_rgb(cube)) {
+        gray
+    } else {
+        cube
+    }
+}
+
+pub fn rgb_to_ansi16(rgb: (u8, u8, u8)) -> u8 {
+    (0..16u8).min_by_key(|&i| distance(rgb, ANSI16_RGB[i as usize])).unwrap_or(7)
+}
+
+// The SGR code for one of the 16 standard colors, as foreground or background.
+fn ansi16_code(idx: u8, background: bool) -> u16 {
+    let base = match (idx < 8, background) {
+        (true, false) => 30,
+        (true, true) => 40,
+        (false, false) => 90,
+        (false, true) => 100
+    };
+    base + (idx % 8) as u16
+}
+
+fn write_color(out: &mut Vec<String>, rgb: Option<(u8, u8, u8)>, idx: Option<u8>, background: bool, color: &Color) {
+    let lead = if background { "48" } else { "38" };
+    match color {
+        Color::TrueColor => match (rgb, idx) {
+            (Some((r, g, b)), _) => out.push(format!("{};2;{};{};{}", lead, r, g, b)),
+            (None, Some(i)) => out.push(format!("{};5;{}", lead, i)),
+            _ => {}
+        },
+        Color::Xterm256 => {
+            let i = idx.or_else(|| rgb.map(rgb_to_xterm));
+            if let Some(i) = i {
+                out.push(format!("{};5;{}", lead, i));
+            }
+        },
+        Color::Standard => {
+            let i = match (idx, rgb) {
+                (Some(i), _) if i < 16 => Some(i),
+                (Some(i), _) => Some(rgb_to_ansi16(xterm_to_rgb(i))),
+                (None, Some(rgb)) => Some(rgb_to_ansi16(rgb)),
+                _ => None
+            };
+            if let Some(i) = i {
+                out.push(ansi16_code(i, background).to_string());
+            }
+        },
+        Color::NoColor => {}
+    }
+}
+
+// Rewrites the parameters of one SGR sequence. Returns None if nothing is left of it.
+fn downgrade_sgr(params: &str, color: &Color) -> Option<String> {
+    if params.is_empty() {
+        // ESC [ m is a full reset.
+        return Some(String::new());
+    }
+
+    // Some games use the ITU ':' form (38:2::r:g:b). Treat it like ';' but drop the empty
+    // colorspace slot it can carry.
+    let colon_form = params.contains(':');
+    let nums: Vec<u16> = params.split([';', ':'])
+        .filter(|p| !(colon_form && p.is_empty()))
+        .map(|p| p.parse().unwrap_or(0))
+        .collect();
+
+    let mut out: Vec<String> = Vec::new();
+    let mut i = 0;
+    while i < nums.len() {
+        let n = nums[i];
+        match n {
+            38 | 48 => {
+                let background = n == 48;
+                match nums.get(i + 1) {
+                    Some(5) => {
+                        let idx = nums.get(i + 2).map(|&v| v.min(255) as u8);
+                        write_color(&mut out, None, idx, background, color);
+                        i += 3;
+                    },
+                    Some(2) => {
+                        let c = |o: usize| nums.get(i + o).map(|&v| v.min(255) as u8).unwrap_or(0);
+                        write_color(&mut out, Some((c(2), c(3), c(4))), None, background, color);
+                        i += 5;
+                    },
+                    _ => i += 1
+                }
+                continue;
+            },
+            30..=37 | 39 | 40..=47 | 49 | 90..=97 | 100..=107 => {
+                if *color != Color::NoColor {
+                    out.push(n.to_string());
+                }
+            },
+            _ => out.push(n.to_string())
+        }
+        i += 1;
+    }
+
+    if out.is_empty() {
+        None
+    } else {
+        Some(out.join(";"))
+    }
+}
+
+pub fn downgrade(s: &str, color: &Color) -> String {
+    if *color == Color::TrueColor || !s.contains('\x1b') {
+        return s.to_string();
+    }
+
+    let mut out = String::with_capacity(s.len());
+    let mut rest = s;
+
+    while let Some(pos) = rest.find("\x1b[") {
+        out.push_str(&rest[..pos]);
+        let seq = &rest[pos + 2..];
+
+        // A CSI sequence is parameter bytes (0x30-0x3F), intermediates (0x20-0x2F) and one
+        // final byte (0x40-0x7E).
+        let end = seq.find(|c: char| ('\x40'..='\x7e').contains(&c));
+        let end = match end {
+            Some(e) if seq[..e].chars().all(|c| ('\x20'..='\x3f').contains(&c)) => e,
+            _ => {
+                // Not a complete CSI sequence. Pass it through untouched.
+                out.push_str("\x1b[");
+                rest = seq;
+                continue;
+            }
+        };
+
+        if seq.as_bytes()[end] == b'm' {
+            if *color != Color::NoColor {
+                if let Some(params) = downgrade_sgr(&seq[..end], color) {
+                    out.push_str("\x1b[");
+                    out.push_str(&params);
+                    out.push('m');
+                }
+            }
+        } else {
+            out.push_str("\x1b[");
+            out.push_str(&seq[..=end]);
+        }
+        rest = &seq[end + 1..];
+    }
+    out.push_str(rest);
+
+    out
+}
+
+// Removes every escape sequence: CSI (colors, cursor movement, MXP line modes), OSC strings
+// ended by BEL or ST, and two-byte ESC x sequences. For output that must be plain text.
+pub fn strip(s: &str) -> String {
+    if !s.contains('\x1b') {
+        return s.to_string();
+    }
+
+    let mut out = String::with_capacity(s.len());
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c != '\x1b' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('[') => {
+                for n in chars.by_ref() {
+                    if ('\x40'..='\x7e').contains(&n) {
+                        break;
+                    }
+                }
+            },
+            Some(']') => {
+                while let Some(n) = chars.next() {
+                    if n == '\x07' {
+                        break;
+                    }
+                    if n == '\x1b' && chars.peek() == Some(&'\\') {
+                        chars.next();
+                        break;
+                    }
+                }
+            },
+            _ => {}
+        }
+    }
+    out
+}
@@ -18,7 +18,7 @@ use futures::{StreamExt, SinkExt};
 use tokio_tungstenite::WebSocketStream;
 use tungstenite::protocol::Message as WsMessage;
 use crate::msg::{Msg2Link, Msg2MudProtocol, Msg2Portal, Msg2PortalFromLink};
-use crate::protocols::{ProtocolCapabilities, ProtocolData, MudData};
+use crate::protocols::{ConnectionStats, ProtocolCapabilities, ProtocolData, MudData};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ServerMsgSessionDisconnect {
@@ -34,6 +34,26 @@ pub struct ServerMsgRequestCapabilities {
     pub id: usize
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ServerMsgRequestStats {
+    pub kind: String,
+    pub id: usize
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ServerMsgClientOption {
+    pub kind: String,
+    pub id: usize,
+    pub name: String,
+    pub value: JsonValue
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ServerMsgMssp {
+    pub kind: String,
+    pub data: HashMap<String, JsonValue>
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct ServerMsgJson {
     pub kind: String,
@@ -67,6 +87,20 @@ pub struct PortalMsgMudData {
     pub data: Vec<MudData>
 }
 
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PortalMsgOobDropped {
+    pub kind: String,
+    pub id: usize,
+    pub cmds: Vec<String>
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct PortalMsgClientStats {
+    pub kind: String,
+    pub id: usize,
+    pub stats: ConnectionStats
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct PortalMsgBroadcast {
     pub kind: String,
@@ -166,6 +200,26 @@ impl<T> LinkProtocol<T> where T: AsyncRead + AsyncWrite + Send + 'static + Unpin
                     wsm = Some(j);
                 }
             },
+            Msg2Link::ClientOobDropped(id, cmds) => {
+                let out = PortalMsgOobDropped {
+                    kind: String::from("client_oob_dropped"),
+                    id,
+                    cmds
+                };
+                if let Ok(j) = serde_json::to_string(&out) {
+                    wsm = Some(j);
+                }
+            },
+            Msg2Link::ClientStats(id, stats) => {
+                let out = PortalMsgClientStats {
+                    kind: String::from("client_stats"),
+                    id,
+                    stats
+                };
+                if let Ok(j) = serde_json::to_string(&out) {
+                    wsm = Some(j);
+                }
+            },
             Msg2Link::ClientDisconnected(id, reason) => {
                 let out = PortalMsgDisconnected {
                     kind: String::from("client_disconnected"),
@@ -247,6 +301,21 @@ impl<T> LinkProtocol<T> where T: AsyncRead + AsyncWrite + Send + 'static + Unpin
                         let _ = self.tx_portal.send(Msg2Portal::FromLink(self.conn_id, Msg2PortalFromLink::ClientMessage(p.id.clone(), p.data))).await;
                     }
                 },
+                "client_stats" => {
+                    if let Ok(p) = serde_json::from_value::<ServerMsgRequestStats>(msg.clone()) {
+                        let _ = self.tx_portal.send(Msg2Portal::FromLink(self.conn_id, Msg2PortalFromLink::ClientRequestStats(p.id))).await;
+                    }
+                },
+                "client_option" => {
+                    if let Ok(p) = serde_json::from_value::<ServerMsgClientOption>(msg.clone()) {
+                        let _ = self.tx_portal.send(Msg2Portal::FromLink(self.conn_id, Msg2PortalFromLink::ClientOption(p.id, p.name, p.value))).await;
+                    }
+                },
+                "mssp" => {
+                    if let Ok(p) = serde_json::from_value::<ServerMsgMssp>(msg.clone()) {
+                        let _ = self.tx_portal.send(Msg2Portal::FromLink(self.conn_id, Msg2PortalFromLink::Mssp(p.data))).await;
+                    }
+                },
                 "broadcast" => {
                     if let Ok(p) = serde_json::from_value::<PortalMsgBroadcast>(msg.clone()) {
                         let _ = self.tx_portal.send(Msg2Portal::Broadcast(p.data)).await;
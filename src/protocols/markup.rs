@@ -0,0 +1,287 @@
+use std::fmt::Write;
+
+// A protocol-neutral color markup, rendered by the portal per client so the game never has to
+// know about ANSI or HTML. The syntax is Evennia's pipe codes:
+//
+//   |r |g |y |b |m |c |w |x   bright red, green, yellow, blue, magenta, cyan, white, gray
+//   |R |G |Y |B |M |C |W |X   the normal versions (|X is black)
+//   |[r ... |[X               the same colors as background
+//   |500 |[500                xterm256 color cube, each digit 0-5 for red, green and blue
+//   |=a ... |=z  |[=a         grayscale ramp, a is black and z is white
+//   |#ff8000 |[#ff8000        truecolor
+//   |h |H                     bold (hilite) on/off
+//   |u |i |* |^               underline, inverse, inverse, blink
+//   |n                        back to normal
+//   |/ |- |_ |>               newline, tab, space, indent
+//   ||                        a literal |
+//
+// Games opt in per message by setting the "markup" kwarg to true on text and prompts.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MarkupColor {
+    // xterm256 palette index. 0-15 are the standard ANSI colors.
+    Indexed(u8),
+    Rgb(u8, u8, u8)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Token {
+    Text(String),
+    Reset,
+    Fg(MarkupColor),
+    Bg(MarkupColor),
+    Bold(bool),
+    Underline,
+    Inverse,
+    Blink
+}
+
+fn base_color(c: char) -> Option<u8> {
+    match c.to_ascii_lowercase() {
+        'x' => Some(0),
+        'r' => Some(1),
+        'g' => Some(2),
+        'y' => Some(3),
+        'b' => Some(4),
+        'm' => Some(5),
+        'c' => Some(6),
+        'w' => Some(7),
+        _ => None
+    }
+}
+
+// Lowercase letters are the bright variants, uppercase the normal ones.
+fn letter_color(c: char) -> Option<MarkupColor> {
+    base_color(c).map(|i| MarkupColor::Indexed(if c.is_ascii_lowercase() { i + 8 } else { i }))
+}
+
+fn gray_color(c: char) -> Option<MarkupColor> {
+    match c {
+        'a' => Some(MarkupColor::Indexed(16)),
+        'z' => Some(MarkupColor::Indexed(231)),
+        'b'..='y' => Some(MarkupColor::Indexed(232 + (c as u8 - b'b'))),
+        _ => None
+    }
+}
+
+// Tries to read a color spec (after the | or |[) from the front of s. Returns the color and how
+// many bytes it used.
+fn parse_color(s: &str) -> Option<(MarkupColor, usize)> {
+    let b = s.as_bytes();
+    match b.first()? {
+        b'#' if b.len() >= 7 && s.is_char_boundary(7) => {
+            let v = u32::from_str_radix(&s[1..7], 16).ok()?;
+            Some((MarkupColor::Rgb((v >> 16) as u8, (v >> 8) as u8, v as u8), 7))
+        },
+        b'=' if b.len() >= 2 => gray_color(b[1] as char).map(|c| (c, 2)),
+        b'0'..=b'5' if b.len() >= 3 && b[1..3].iter().all(|d| (b'0'..=b'5').contains(d)) => {
+            let idx = 16 + 36 * (b[0] - b'0') + 6 * (b[1] - b'0') + (b[2] - b'0');
+            Some((MarkupColor::Indexed(idx), 3))
+        },
+        _ => letter_color(b[0] as char).map(|c| (c, 1))
+    }
+}
+
+pub fn parse(s: &str) -> Vec<Token> {
+    let mut out = Vec::new();
+    let mut text = String::new();
+    let mut rest = s;
+
+    while let Some(pos) = rest.find('|') {
+        text.push_str(&rest[..pos]);
+        let code = &rest[pos + 1..];
+
+        let mut token = None;
+        let used = match code.chars().next() {
+            Some('|') => { text.push('|'); 1 },
+            Some('/') => { text.push('\n'); 1 },
+            Some('-') => { text.push('\t'); 1 },
+            Some('_') => { text.push(' '); 1 },
+            Some('>') => { text.push_str("    "); 1 },
+            Some('n') => { token = Some(Token::Reset); 1 },
+            Some('h') => { token = Some(Token::Bold(true)); 1 },
+            Some('H') => { token = Some(Token::Bold(false)); 1 },
+            Some('u') => { token = Some(Token::Underline); 1 },
+            Some('i') | Some('*') => { token = Some(Token::Inverse); 1 },
+            Some('^') => { token = Some(Token::Blink); 1 },
+            Some('[') => match parse_color(&code[1..]) {
+                Some((c, n)) => { token = Some(Token::Bg(c)); n + 1 },
+                None => 0
+            },
+            Some(_) => match parse_color(code) {
+                Some((c, n)) => { token = Some(Token::Fg(c)); n },
+                None => 0
+            },
+            None => 0
+        };
+
+        if used == 0 {
+            // Not a code we know, so it's just a pipe in the text.
+            text.push('|');
+        }
+        if let Some(t) = token {
+            if !text.is_empty() {
+                out.push(Token::Text(std::mem::take(&mut text)));
+            }
+            out.push(t);
+        }
+        rest = &code[used..];
+    }
+    text.push_str(rest);
+    if !text.is_empty() {
+        out.push(Token::Text(text));
+    }
+
+    out
+}
+
+// Renders to ANSI at full fidelity. Telnet then steps it down with ansi::downgrade.
+pub fn to_ansi(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut styled = false;
+
+    for token in parse(s) {
+        let sgr = match token {
+            Token::Text(t) => {
+                out.push_str(&t);
+                continue;
+            },
+            Token::Reset => "0".to_string(),
+            Token::Fg(MarkupColor::Indexed(i)) if i < 8 => format!("{}", 30 + i),
+            Token::Fg(MarkupColor::Indexed(i)) if i < 16 => format!("{}", 90 + i - 8),
+            Token::Fg(MarkupColor::Indexed(i)) => format!("38;5;{}", i),
+            Token::Fg(MarkupColor::Rgb(r, g, b)) => format!("38;2;{};{};{}", r, g, b),
+            Token::Bg(MarkupColor::Indexed(i)) if i < 8 => format!("{}", 40 + i),
+            Token::Bg(MarkupColor::Indexed(i)) if i < 16 => format!("{}", 100 + i - 8),
+            Token::Bg(MarkupColor::Indexed(i)) => format!("48;5;{}", i),
+            Token::Bg(MarkupColor::Rgb(r, g, b)) => format!("48;2;{};{};{}", r, g, b),
+            Token::Bold(true) => "1".to_string(),
+            Token::Bold(false) => "22".to_string(),
+            Token::Underline => "4".to_string(),
+            Token::Inverse => "7".to_string(),
+            Token::Blink => "5".to_string()
+        };
+        let _ = write!(out, "\x1b[{}m", sgr);
+        styled = true;
+    }
+
+    // Don't let colors bleed into whatever comes next.
+    if styled {
+        out.push_str("\x1b[0m");
+    }
+    out
+}
+
+#[derive(Default, Clone, PartialEq, Eq)]
+struct HtmlStyle {
+    fg: Option<MarkupColor>,
+    bg: Option<MarkupColor>,
+    bold: bool,
+    underline: bool,
+    inverse: bool,
+    blink: bool
+}
+
+impl HtmlStyle {
+    fn is_plain(&self) -> bool {
+        *self == HtmlStyle::default()
+    }
+
+    fn open_tag(&self) -> String {
+        let mut classes = Vec::new();
+        let mut styles = Vec::new();
+
+        // Like a terminal, bold brightens the 8 normal colors.
+        match self.fg {
+            Some(MarkupColor::Indexed(i)) if self.bold && i < 8 => classes.push(format!("color-{:03}", i + 8)),
+            Some(MarkupColor::Indexed(i)) => classes.push(format!("color-{:03}", i)),
+            Some(MarkupColor::Rgb(r, g, b)) => styles.push(format!("color: #{:02x}{:02x}{:02x}", r, g, b)),
+            None => {}
+        }
+        match self.bg {
+            Some(MarkupColor::Indexed(i)) => classes.push(format!("bgcolor-{:03}", i)),
+            Some(MarkupColor::Rgb(r, g, b)) => styles.push(format!("background-color: #{:02x}{:02x}{:02x}", r, g, b)),
+            None => {}
+        }
+        if self.bold {
+            styles.push("font-weight: bold".to_string());
+        }
+        if self.underline {
+            classes.push("underline".to_string());
+        }
+        if self.inverse {
+            classes.push("inverse".to_string());
+        }
+        if self.blink {
+            classes.push("blink".to_string());
+        }
+
+        let mut tag = String::from("<span");
+        if !classes.is_empty() {
+            let _ = write!(tag, " class=\"{}\"", classes.join(" "));
+        }
+        if !styles.is_empty() {
+            let _ = write!(tag, " style=\"{}\"", styles.join("; "));
+        }
+        tag.push('>');
+        tag
+    }
+}
+
+fn html_escape(s: &str, out: &mut String) {
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            _ => out.push(c)
+        }
+    }
+}
+
+// Renders to HTML for the webclient, using the color-NNN/bgcolor-NNN classes from its
+// stylesheet. Truecolor has no class and goes in an inline style instead.
+pub fn to_html(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    let mut style = HtmlStyle::default();
+    let mut open = false;
+
+    for token in parse(s) {
+        match token {
+            Token::Text(t) => {
+                if !open && !style.is_plain() {
+                    out.push_str(&style.open_tag());
+                    open = true;
+                }
+                html_escape(&t, &mut out);
+                continue;
+            },
+            Token::Reset => style = HtmlStyle::default(),
+            Token::Fg(c) => style.fg = Some(c),
+            Token::Bg(c) => style.bg = Some(c),
+            Token::Bold(b) => style.bold = b,
+            Token::Underline => style.underline = true,
+            Token::Inverse => style.inverse = true,
+            Token::Blink => style.blink = true
+        }
+        // The style changed. The next text opens a fresh span.
+        if open {
+            out.push_str("</span>");
+            open = false;
+        }
+    }
+    if open {
+        out.push_str("</span>");
+    }
+    out
+}
+
+// Just the text, for screen readers and anything else that can't use styling.
+pub fn to_plain(s: &str) -> String {
+    parse(s).into_iter().filter_map(|t| match t {
+        Token::Text(t) => Some(t),
+        _ => None
+    }).collect()
+}
@@ -7,9 +7,15 @@ use crate::msg::Msg2MudProtocol;
 use serde::{Serialize, Deserialize};
 use serde_json::Value as JsonValue;
 
+pub mod ansi;
 pub mod link;
+pub mod markup;
+pub mod mxp;
+pub mod oob;
+pub mod screenreader;
 pub mod telnet;
 pub mod websocket;
+pub mod wrap;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct MudData {
@@ -24,6 +30,18 @@ pub struct ProtocolData {
     pub capabilities: ProtocolCapabilities
 }
 
+// Traffic counters for one connection, as reported to the game on request. wire_bytes differ
+// from bytes only when the protocol compresses its stream (telnet MCCP).
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ConnectionStats {
+    pub connected_secs: u64,
+    pub idle_secs: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub wire_bytes_in: u64,
+    pub wire_bytes_out: u64
+}
+
 // This is received by whatever handles connections once they are ready to join the game.
 #[derive(Debug, Clone)]
 pub struct ProtocolLink {
@@ -55,12 +73,37 @@ pub enum Color {
     TrueColor = 3
 }
 
+impl Color {
+    // The names players use for color depths, e.g. in //color.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_lowercase().as_str() {
+            "none" | "off" | "0" => Some(Color::NoColor),
+            "16" | "ansi" | "standard" => Some(Color::Standard),
+            "256" | "xterm" | "xterm256" => Some(Color::Xterm256),
+            "truecolor" | "24bit" | "rgb" => Some(Color::TrueColor),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Color::NoColor => "none",
+            Color::Standard => "16",
+            Color::Xterm256 => "256",
+            Color::TrueColor => "truecolor"
+        }
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct ProtocolCapabilities {
     pub protocol: Protocol,
     pub encryption: bool,
     pub client_name: String,
     pub client_version: String,
+    // Every terminal type the client gave while we cycled through TTYPE, in order. The first is
+    // usually the client name, and MTTS clients end with "MTTS <bitfield>".
+    pub terminal_types: Vec<String>,
     pub host_address: String,
     pub host_port: u16,
     pub host_names: Vec<String>,
@@ -87,7 +130,14 @@ pub struct ProtocolCapabilities {
     pub vt100: bool,
     pub osc_color_palette: bool,
     pub proxy: bool,
-    pub mnes: bool
+    pub mnes: bool,
+    // True while the client has agreed to hide what the user types, e.g. for a password.
+    pub secret_input: bool,
+    // GMCP packages the client announced via Core.Supports.*, mapped to their version.
+    pub gmcp_supports: HashMap<String, u32>,
+    // Every variable the client reported over NEW-ENVIRON (MNES). These are client-supplied and
+    // unverified, IPADDRESS especially.
+    pub environ: HashMap<String, String>
 }
 
 impl Default for ProtocolCapabilities {
@@ -98,6 +148,7 @@ impl Default for ProtocolCapabilities {
             height: 24,
             client_name: "UNKNOWN".to_string(),
             client_version: "UNKNOWN".to_string(),
+            terminal_types: vec![],
             host_address: "UNKNOWN".to_string(),
             host_port: 0,
             host_names: vec![],
@@ -123,7 +174,10 @@ impl Default for ProtocolCapabilities {
             vt100: false,
             osc_color_palette: false,
             proxy: false,
-            mnes: false
+            mnes: false,
+            secret_input: false,
+            gmcp_supports: Default::default(),
+            environ: Default::default()
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,162 @@
+use std::collections::HashMap;
+
+use serde_json::Value as JsonValue;
+
+// MXP line modes, switched with ESC [ <n> z. The "line" modes only last until the next newline,
+// after which the client falls back to whatever was last locked in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MxpMode {
+    Open = 0,
+    Secure = 1,
+    Locked = 2,
+    Reset = 3,
+    TempSecure = 4,
+    LockOpen = 5,
+    LockSecure = 6,
+    LockLocked = 7
+}
+
+impl MxpMode {
+    pub fn escape(&self) -> String {
+        format!("\x1b[{}z", *self as u8)
+    }
+
+    // Games pick a mode per message with the "mxp" kwarg: true means secure, or a mode name.
+    pub fn from_kwarg(v: &JsonValue) -> Option<Self> {
+        match v {
+            JsonValue::Bool(true) => Some(MxpMode::Secure),
+            JsonValue::String(s) => match s.to_lowercase().as_str() {
+                "open" => Some(MxpMode::Open),
+                "secure" => Some(MxpMode::Secure),
+                "locked" => Some(MxpMode::Locked),
+                _ => None
+            },
+            _ => None
+        }
+    }
+}
+
+// Puts every line of MXP markup into the given line mode. Since line modes reset at each
+// newline, every line needs its own escape.
+pub fn to_mxp(markup: &str, mode: MxpMode) -> String {
+    let esc = mode.escape();
+    let mut out = String::with_capacity(markup.len() + esc.len());
+    for (i, line) in markup.split('\n').enumerate() {
+        if i > 0 {
+            out.push('\n');
+        }
+        if !line.is_empty() && line != "\r" {
+            out.push_str(&esc);
+        }
+        out.push_str(line);
+    }
+    out
+}
+
+// Turns MXP markup into plain text for clients that didn't negotiate MXP. Tags are removed,
+// <BR> becomes a newline and entities are expanded. Entities that the game defines with
+// <!ENTITY> are remembered so later text using them still reads correctly.
+#[derive(Debug, Default, Clone)]
+pub struct MxpTranslator {
+    entities: HashMap<String, String>
+}
+
+impl MxpTranslator {
+    pub fn to_plain(&mut self, markup: &str) -> String {
+        let mut out = String::with_capacity(markup.len());
+        let mut chars = markup.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '<' => {
+                    let mut tag = String::new();
+                    let mut quote: Option<char> = None;
+                    for t in chars.by_ref() {
+                        match quote {
+                            Some(q) if t == q => quote = None,
+                            Some(_) => {},
+                            None if t == '"' || t == '\'' => quote = Some(t),
+                            None if t == '>' => break,
+                            None => {}
+                        }
+                        tag.push(t);
+                    }
+                    self.handle_tag(&tag, &mut out);
+                },
+                '&' => {
+                    let mut name = String::new();
+                    while let Some(&n) = chars.peek() {
+                        if n == ';' || n.is_whitespace() || n == '&' || n == '<' || name.len() > 32 {
+                            break;
+                        }
+                        name.push(n);
+                        chars.next();
+                    }
+                    if chars.peek() == Some(&';') {
+                        chars.next();
+                        match self.lookup_entity(&name) {
+                            Some(v) => out.push_str(&v),
+                            None => {
+                                out.push('&');
+                                out.push_str(&name);
+                                out.push(';');
+                            }
+                        }
+                    } else {
+                        out.push('&');
+                        out.push_str(&name);
+                    }
+                },
+                _ => out.push(c)
+            }
+        }
+
+        out
+    }
+
+    fn handle_tag(&mut self, tag: &str, out: &mut String) {
+        let tag = tag.trim();
+        if let Some(def) = tag.strip_prefix("!") {
+            let mut parts = def.splitn(3, char::is_whitespace);
+            let kind = parts.next().unwrap_or("");
+            if kind.eq_ignore_ascii_case("ENTITY") || kind.eq_ignore_ascii_case("EN") {
+                if let (Some(name), Some(rest)) = (parts.next(), parts.next()) {
+                    self.entities.insert(name.to_lowercase(), Self::entity_value(rest));
+                }
+            }
+            return;
+        }
+
+        let name = tag.split_whitespace().next().unwrap_or("");
+        if name.eq_ignore_ascii_case("br") || name.eq_ignore_ascii_case("br/") {
+            out.push_str("\r\n");
+        }
+    }
+
+    // The value of an <!ENTITY> is either quoted or runs to the next whitespace.
+    fn entity_value(rest: &str) -> String {
+        let rest = rest.trim_start();
+        for q in ['"', '\''] {
+            if let Some(inner) = rest.strip_prefix(q) {
+                return inner.split(q).next().unwrap_or("").to_string();
+            }
+        }
+        rest.split_whitespace().next().unwrap_or("").to_string()
+    }
+
+    fn lookup_entity(&self, name: &str) -> Option<String> {
+        if let Some(num) = name.strip_prefix('#') {
+            return num.parse::<u32>().ok().and_then(char::from_u32).map(String::from);
+        }
+        let builtin = match name.to_lowercase().as_str() {
+            "lt" => Some("<"),
+            "gt" => Some(">"),
+            "amp" => Some("&"),
+            "quot" => Some("\""),
+            "apos" => Some("'"),
+            "nbsp" => Some(" "),
+            _ => None
+        };
+        builtin.map(String::from).or_else(|| self.entities.get(&name.to_lowercase()).cloned())
+    }
+}
@@ -0,0 +1,83 @@
+use serde_json::Value as JsonValue;
+
+use crate::{
+    config::OobConfig,
+    protocols::{MudData, Protocol, ProtocolCapabilities}
+};
+
+// Where an outbound OOB MudData (anything that isn't text, prompt or mssp) should go for a
+// given client. Telnet and websocket share this so that both make the same decision.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OobRoute {
+    // The websocket client takes MudData as-is.
+    Json,
+    Gmcp,
+    Msdp,
+    // No OOB channel, but a fallback template turned it into text.
+    Text(String),
+    // No OOB channel and no fallback. The game is told it was dropped.
+    Drop
+}
+
+pub fn route(capabilities: &ProtocolCapabilities, config: &OobConfig, d: &MudData) -> OobRoute {
+    match capabilities.protocol {
+        Protocol::WebSocket if capabilities.oob => return OobRoute::Json,
+        Protocol::Telnet if capabilities.gmcp => return OobRoute::Gmcp,
+        Protocol::Telnet if capabilities.msdp => return OobRoute::Msdp,
+        _ => {}
+    }
+
+    match config.fallback.get(&d.cmd) {
+        Some(template) => OobRoute::Text(render_fallback(template, d)),
+        None => OobRoute::Drop
+    }
+}
+
+fn value_to_text(v: &JsonValue) -> String {
+    match v {
+        JsonValue::String(s) => s.clone(),
+        JsonValue::Null => String::new(),
+        other => other.to_string()
+    }
+}
+
+// Replaces {name} with the kwarg of that name, {N} with args[N] and {cmd} with the command
+// name. Unknown placeholders become empty strings. {{ and }} produce literal braces.
+pub fn render_fallback(template: &str, d: &MudData) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut chars = template.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                out.push('{');
+            },
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                out.push('}');
+            },
+            '{' => {
+                let mut key = String::new();
+                for k in chars.by_ref() {
+                    if k == '}' {
+                        break;
+                    }
+                    key.push(k);
+                }
+                if key == "cmd" {
+                    out.push_str(&d.cmd);
+                } else if let Ok(idx) = key.parse::<usize>() {
+                    if let Some(v) = d.args.get(idx) {
+                        out.push_str(&value_to_text(v));
+                    }
+                } else if let Some(v) = d.kwargs.get(&key) {
+                    out.push_str(&value_to_text(v));
+                }
+            },
+            _ => out.push(c)
+        }
+    }
+
+    out
+}
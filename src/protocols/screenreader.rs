@@ -0,0 +1,157 @@
+use crate::protocols::ansi;
+
+// Output transform for players on screen readers, applied when capabilities.screen_reader is
+// set. Colors and other escapes are removed, borders and rules made of box drawing, table
+// border symbols or one repeated symbol are dropped, and long runs of one symbol are read out as a count instead of
+// character by character. Column padding is collapsed since it only adds pauses.
+
+// Runs of the same symbol at least this long are described instead of kept.
+const SYMBOL_RUN: usize = 4;
+
+fn is_box(c: char) -> bool {
+    // Box Drawing and Block Elements.
+    ('\u{2500}'..='\u{259f}').contains(&c)
+}
+
+// Symbols that games draw horizontal rules with. A line of !!!! or ???? is still speech.
+fn is_rule(c: char) -> bool {
+    matches!(c, '-' | '=' | '_' | '~' | '*' | '#' | '+' | '.' | ':')
+}
+
+// What ASCII tables are drawn with, in any mix, as in +------+------+ or |=====|=====|.
+fn is_border(c: char) -> bool {
+    matches!(c, '+' | '-' | '=' | '|' | '_' | '~' | '*' | ':')
+}
+
+// A border or rule: one symbol over and over, or nothing but box drawing and table borders.
+// Anything else, such as a [#####     ] gauge, a ###.. gauge or an arrow like <--->, carries
+// meaning and goes to transform_line.
+fn is_separator_line(line: &str) -> bool {
+    let visible: Vec<char> = line.chars().filter(|c| !c.is_whitespace()).collect();
+    if visible.len() < SYMBOL_RUN {
+        return false;
+    }
+    visible.iter().all(|c| is_box(*c) || is_border(*c)) || (is_rule(visible[0]) && visible.iter().all(|c| *c == visible[0]))
+}
+
+fn symbol_name(c: char) -> String {
+    match c {
+        '#' => "hashes".to_string(),
+        '*' => "stars".to_string(),
+        '=' => "equals signs".to_string(),
+        '-' => "dashes".to_string(),
+        '_' => "underscores".to_string(),
+        '~' => "tildes".to_string(),
+        '.' => "dots".to_string(),
+        '+' => "plus signs".to_string(),
+        '|' => "bars".to_string(),
+        '/' => "slashes".to_string(),
+        '<' => "less-than signs".to_string(),
+        '>' => "greater-than signs".to_string(),
+        c => format!("{} characters", c)
+    }
+}
+
+fn transform_line(line: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    let chars: Vec<char> = line.chars().collect();
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        let run = chars[i..].iter().take_while(|&&n| n == c).count();
+
+        if is_box(c) || c.is_whitespace() {
+            out.push(' ');
+        } else if c.is_ascii_punctuation() && run >= SYMBOL_RUN {
+            // A gauge like [#####     ] becomes [5 hashes ].
+            out.push(' ');
+            out.push_str(&format!("{} {}", run, symbol_name(c)));
+            out.push(' ');
+        } else {
+            out.extend(std::iter::repeat_n(c, run));
+        }
+        i += run;
+    }
+
+    out.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+pub fn text(s: &str) -> String {
+    let s = ansi::strip(s);
+    let mut out = String::with_capacity(s.len());
+    let mut lines = s.split('\n').peekable();
+    let mut last_blank = false;
+
+    while let Some(line) = lines.next() {
+        let (body, cr) = match line.strip_suffix('\r') {
+            Some(b) => (b, "\r"),
+            None => (line, "")
+        };
+        let last = lines.peek().is_none();
+
+        if is_separator_line(body) {
+            // Drop the whole line, ending included, unless it's the final piece of the text.
+            if last {
+                break;
+            }
+            continue;
+        }
+
+        let t = transform_line(body);
+        let blank = t.is_empty();
+        if blank && last_blank && !last {
+            // One blank line is enough for a pause.
+            continue;
+        }
+        last_blank = blank;
+
+        out.push_str(&t);
+        out.push_str(cr);
+        if !last {
+            out.push('\n');
+        }
+    }
+    out
+}
+
+// Prompts get a spoken label so they aren't mistaken for ordinary output.
+pub fn prompt(s: &str) -> String {
+    let t = text(s);
+    let t = t.trim();
+    if t.is_empty() {
+        return String::new();
+    }
+    format!("Prompt: {}", t)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn separators() {
+        assert!(is_separator_line("----------"));
+        assert!(is_separator_line("  = = = = =  "));
+        assert!(is_separator_line("\u{250c}\u{2500}\u{2500}\u{2500}\u{2510}"));
+        assert!(is_separator_line("+------+------+"));
+        assert!(is_separator_line("|======|======|"));
+        assert!(is_separator_line("  -=-=-=-=-=-=  "));
+        assert!(is_separator_line("+---+ +---+"));
+        assert!(is_separator_line("\u{251c}\u{2500}\u{2500}+--\u{2524}"));
+        assert!(!is_separator_line("###.."));
+        assert!(!is_separator_line("| Name | Level |"));
+        assert!(!is_separator_line("[#####     ]"));
+        assert!(!is_separator_line("!!!!"));
+        assert!(!is_separator_line("<--->"));
+        assert!(!is_separator_line("---"));
+    }
+
+    #[test]
+    fn gauges_are_described() {
+        assert_eq!(text("HP [#####     ]"), "HP [ 5 hashes ]");
+        assert_eq!(text("=========\nHello\n<---->"), "Hello\n< 4 dashes >");
+        assert_eq!(text("+------+------+\n| Name | Level |\n+------+------+"), "| Name | Level |\n");
+        assert_eq!(text("!!!!"), "4 ! characters");
+    }
+}
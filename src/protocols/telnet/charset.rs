@@ -0,0 +1,103 @@
+use bytes::{BufMut, Bytes, BytesMut};
+
+// RFC 2066 CHARSET sub-negotiation commands.
+pub const REQUEST: u8 = 1;
+pub const ACCEPTED: u8 = 2;
+pub const REJECTED: u8 = 3;
+
+// The encodings the portal can transcode to and from. The game always deals in Unicode; the
+// portal converts per client. Decoding never fails: bytes that don't fit the encoding become
+// U+FFFD, and characters the encoding can't represent are sent as '?'.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Charset {
+    Utf8,
+    Latin1,
+    Ascii,
+    Cp437
+}
+
+// Code page 437, bytes 0x80 to 0xFF. The lower half is plain ASCII.
+static CP437_HIGH: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å',
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ',
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»',
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐',
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧',
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀',
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩',
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{a0}'
+];
+
+impl Charset {
+    // Accepts the usual IANA names and aliases clients send, case-insensitively.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.trim().to_uppercase().as_str() {
+            "UTF-8" | "UTF8" => Some(Charset::Utf8),
+            "ISO-8859-1" | "ISO_8859-1" | "ISO8859-1" | "LATIN1" | "LATIN-1" | "L1" => Some(Charset::Latin1),
+            "US-ASCII" | "ASCII" | "ANSI_X3.4-1968" => Some(Charset::Ascii),
+            "CP437" | "IBM437" | "437" => Some(Charset::Cp437),
+            _ => None
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Charset::Utf8 => "UTF-8",
+            Charset::Latin1 => "ISO-8859-1",
+            Charset::Ascii => "US-ASCII",
+            Charset::Cp437 => "CP437"
+        }
+    }
+
+    pub fn decode(&self, data: &[u8]) -> String {
+        match self {
+            Charset::Utf8 => String::from_utf8_lossy(data).to_string(),
+            Charset::Latin1 => data.iter().map(|&b| b as char).collect(),
+            Charset::Ascii => data.iter().map(|&b| if b < 0x80 { b as char } else { char::REPLACEMENT_CHARACTER }).collect(),
+            Charset::Cp437 => data.iter().map(|&b| if b < 0x80 { b as char } else { CP437_HIGH[(b - 0x80) as usize] }).collect()
+        }
+    }
+
+    pub fn encode(&self, s: &str) -> Bytes {
+        match self {
+            Charset::Utf8 => Bytes::from(s.to_string()),
+            Charset::Latin1 => s.chars().map(|c| if (c as u32) < 0x100 { c as u8 } else { b'?' }).collect(),
+            Charset::Ascii => s.chars().map(|c| if c.is_ascii() { c as u8 } else { b'?' }).collect(),
+            Charset::Cp437 => s.chars().map(|c| {
+                if c.is_ascii() {
+                    c as u8
+                } else {
+                    CP437_HIGH.iter().position(|&h| h == c).map(|i| i as u8 + 0x80).unwrap_or(b'?')
+                }
+            }).collect()
+        }
+    }
+}
+
+// Builds IAC SB CHARSET REQUEST ;<name>;<name>... IAC SE for the names we're willing to use.
+pub fn request(charsets: &[Charset]) -> Bytes {
+    let mut out = BytesMut::new();
+    out.put_u8(REQUEST);
+    for c in charsets {
+        out.put_u8(b';');
+        out.put(c.name().as_bytes());
+    }
+    out.freeze()
+}
+
+// Parses the names out of a REQUEST payload (minus the command byte). The first byte is the
+// separator the sender chose. Translation tables ([TTABLE]) aren't supported and are skipped.
+pub fn parse_request(data: &[u8]) -> Vec<String> {
+    let mut data = data;
+    if data.starts_with(b"[TTABLE]") && data.len() > 9 {
+        data = &data[9..];
+    }
+    let (&sep, rest) = match data.split_first() {
+        Some(v) => v,
+        None => return Vec::new()
+    };
+    rest.split(|&b| b == sep)
+        .filter(|n| !n.is_empty())
+        .map(|n| String::from_utf8_lossy(n).to_string())
+        .collect()
+}
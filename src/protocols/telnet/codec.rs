@@ -1,19 +1,17 @@
 use tokio_util::codec::{Encoder, Decoder};
 use bytes::{
     BytesMut, Buf, BufMut, Bytes,
-    buf::Writer,
 };
 
 use std::{
     io
 };
-use std::io::{Cursor, Read, Write};
 use flate2::{
-    write::{ZlibEncoder},
-    write::{ZlibDecoder},
-    Compression
+    Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status
 };
 
+use serde::{Serialize, Deserialize};
+
 
 use super::codes;
 
@@ -22,7 +20,7 @@ use serde_json::{Result as JsonResult, Value as JsonValue};
 
 
 // TelnetEvents are the bread and butter of this Codec.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub enum TelnetEvent {
     // WILL|WONT|DO|DONT <OPTION>
     Negotiate(u8, u8),
@@ -37,10 +35,83 @@ pub enum TelnetEvent {
     Command(u8)
 }
 
+// Per-option limits on how long a subnegotiation may grow, counted in bytes as they arrive
+// (escapes included) between IAC SB <option> and IAC SE. Anything longer is dropped.
+pub fn max_subnegotiation(op: u8) -> usize {
+    match op {
+        // Two 16-bit values, each byte possibly doubled.
+        codes::NAWS => 8,
+        codes::START_TLS | codes::MCCP2 | codes::MCCP3 => 16,
+        codes::MTTS => 256,
+        codes::CHARSET => 1024,
+        codes::MNES => 4096,
+        codes::GMCP | codes::MSDP => 32768,
+        _ => 1024
+    }
+}
+
+// Doubles every IAC so that 0xFF in data or subnegotiation payloads isn't read as a command.
+fn escape_iac(data: &[u8], out: &mut BytesMut) {
+    for chunk in data.split_inclusive(|b| *b == codes::IAC) {
+        out.extend_from_slice(chunk);
+        if chunk.last() == Some(&codes::IAC) {
+            out.put_u8(codes::IAC);
+        }
+    }
+}
+
+// Turns IAC IAC back into a single IAC.
+fn unescape_iac(data: &[u8]) -> Bytes {
+    if !data.contains(&codes::IAC) {
+        return Bytes::copy_from_slice(data);
+    }
+    let mut out = BytesMut::with_capacity(data.len());
+    let mut iter = data.iter();
+    while let Some(b) = iter.next() {
+        out.put_u8(*b);
+        if *b == codes::IAC {
+            // Skip the second half of the pair. A lone IAC followed by something else is
+            // malformed, and the something else is kept.
+            if let Some(n) = iter.as_slice().first() {
+                if *n == codes::IAC {
+                    iter.next();
+                }
+            }
+        }
+    }
+    out.freeze()
+}
+
+// Looks for the IAC SE that ends a subnegotiation, starting at the payload. Ok is the position
+// of that IAC. Err is how many bytes can be thrown away without losing track of escapes, which
+// is all of them unless the last one is the first half of an IAC pair.
+fn find_sub_end(src: &[u8], from: usize) -> Result<usize, usize> {
+    let mut i = from;
+    while i < src.len() {
+        if src[i] == codes::IAC {
+            match src.get(i + 1) {
+                Some(&codes::SE) => return Ok(i),
+                Some(_) => i += 2,
+                None => return Err(i)
+            }
+        } else {
+            i += 1;
+        }
+    }
+    Err(src.len())
+}
+
 impl From<TelnetEvent> for Bytes {
     fn from(src: TelnetEvent) -> Self {
         match src {
-            TelnetEvent::Data(data) => data,
+            TelnetEvent::Data(data) => {
+                if !data.contains(&codes::IAC) {
+                    return data;
+                }
+                let mut out = BytesMut::with_capacity(data.len() + 8);
+                escape_iac(&data, &mut out);
+                out.freeze()
+            },
             TelnetEvent::Negotiate(comm, op) => {
                 let mut out = BytesMut::with_capacity(3);
                 out.extend(&[codes::IAC, comm, op]);
@@ -49,7 +120,7 @@ impl From<TelnetEvent> for Bytes {
             TelnetEvent::SubNegotiate(op, data) => {
                 let mut out = BytesMut::with_capacity(5 + data.len());
                 out.extend(&[codes::IAC, codes::SB, op]);
-                out.extend(data);
+                escape_iac(&data, &mut out);
                 out.extend(&[codes::IAC, codes::SE]);
                 out.freeze()
             },
@@ -89,36 +160,20 @@ impl TelnetEvent {
                         }
                     },
                     codes::SB => {
-                        // Since the valid signature is IAC SB <option> <data> IAC SE, and data might be empty, we need at least 5 bytes.
-                        if src.len() > 4 {
-                            let mut escape_next = false;
-                            if let Some(ipos) = src.as_ref().windows(2).enumerate().find_map(|(i, b)| {
-                                if escape_next {
-                                    escape_next = false;
-                                    return None;
-                                }
-
-                                if b[0] == codes::IAC {
-                                    if b[1] == codes::SE {
-                                        return Some(i);
-                                    } else if b[1] == codes::IAC {
-                                        escape_next = true;
-                                    }
-                                }
-                                None
-                            }) {
-                                // Split off any available up to an IAC and stuff it in the sub data buffer.
-                                let mut data = src.split_to(ipos);
-                                src.advance(2);
-                                let discard = data.split_to(3);
-                                let answer = TelnetEvent::SubNegotiate(discard[2], data.freeze());
-                                Some(answer)
-                            } else {
-                                None
-                            }
-                        } else {
+                        // The valid signature is IAC SB <option> <data> IAC SE. The data may be
+                        // empty and has its IACs doubled.
+                        if src.len() < 5 {
                             // Not enough bytes for sub-negotiation...yet.
-                            None
+                            return None;
+                        }
+                        match find_sub_end(src.as_ref(), 3) {
+                            Ok(end) => {
+                                let op = src[2];
+                                let data = unescape_iac(&src[3..end]);
+                                src.advance(end + 2);
+                                Some(TelnetEvent::SubNegotiate(op, data))
+                            },
+                            Err(_) => None
                         }
                     },
                     _ => {
@@ -143,21 +198,170 @@ impl TelnetEvent {
     }
 }
 
-#[derive(Debug, Default)]
+// Byte counters for one connection. raw is what the protocol layer saw, wire is what actually
+// crossed the socket. They only differ while MCCP2 (out) or MCCP3 (in) is active.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct CompressionStats {
+    pub raw_in: u64,
+    pub wire_in: u64,
+    pub raw_out: u64,
+    pub wire_out: u64
+}
+
 pub struct TelnetCodec {
-    max_buffer: usize,
-    decoder: Option<ZlibDecoder<Writer<BytesMut>>>,
-    encoder: Option<ZlibEncoder<Writer<BytesMut>>>,
+    // Set while skipping the rest of a subnegotiation that went over max_subnegotiation.
+    discarding: bool,
+    compression_level: u32,
+    // Set once we've agreed to MCCP3. Until then a client's IAC SB MCCP3 IAC SE is just another
+    // subnegotiation and doesn't switch on decompression.
+    mccp3_agreed: bool,
+    // Present while MCCP3 is active, inflating everything the client sends.
+    decompress: Option<Decompress>,
+    // Present while MCCP2 is active, deflating everything we send.
+    compress: Option<Compress>,
+    // Bytes that have been decompressed (if needed) but not yet parsed into TelnetEvents.
+    inbound: BytesMut,
+    stats: CompressionStats
 }
 
 impl TelnetCodec {
-    pub fn new(max_buffer: usize) -> Self {
+    pub fn new(compression_level: u32) -> Self {
 
         TelnetCodec {
-            max_buffer,
-            decoder: None,
-            encoder: None
+            discarding: false,
+            compression_level: compression_level.min(9),
+            mccp3_agreed: false,
+            decompress: None,
+            compress: None,
+            inbound: BytesMut::new(),
+            stats: Default::default()
+        }
+    }
+
+    pub fn stats(&self) -> &CompressionStats {
+        &self.stats
+    }
+
+    pub fn compressing(&self) -> bool {
+        self.compress.is_some()
+    }
+
+    pub fn decompressing(&self) -> bool {
+        self.decompress.is_some()
+    }
+
+    // Whether the client may start MCCP3. A stream it already started runs until it ends it.
+    pub fn set_mccp3_agreed(&mut self, agreed: bool) {
+        self.mccp3_agreed = agreed;
+    }
+
+    // Hands back any bytes that have been read but not parsed yet. Used when the stream
+    // underneath changes, as with STARTTLS.
+    pub fn take_inbound(&mut self) -> BytesMut {
+        self.inbound.split()
+    }
+
+    // Moves bytes off the socket into the inbound buffer, inflating them first if MCCP3 is on.
+    // If the client ends its zlib stream, whatever follows is plain telnet again. If the stream
+    // is corrupt there's no way to resync, so decompression is switched off and the rest of
+    // that chunk is discarded.
+    fn feed(&mut self, mut data: &[u8]) {
+        self.stats.wire_in += data.len() as u64;
+
+        while let Some(decompress) = &mut self.decompress {
+            if data.is_empty() {
+                return;
+            }
+            let mut chunk = [0u8; 4096];
+            let before_in = decompress.total_in();
+            let before_out = decompress.total_out();
+            let result = decompress.decompress(data, &mut chunk, FlushDecompress::None);
+            let consumed = (decompress.total_in() - before_in) as usize;
+            let produced = (decompress.total_out() - before_out) as usize;
+
+            self.inbound.extend_from_slice(&chunk[..produced]);
+            self.stats.raw_in += produced as u64;
+            data = &data[consumed..];
+
+            match result {
+                Ok(Status::StreamEnd) => {
+                    self.decompress = None;
+                },
+                Ok(_) => {
+                    if consumed == 0 && produced == 0 {
+                        // Nothing more can be done until the client sends more.
+                        return;
+                    }
+                },
+                Err(_) => {
+                    self.decompress = None;
+                    return;
+                }
+            }
+        }
+
+        self.stats.raw_in += data.len() as u64;
+        self.inbound.extend_from_slice(data);
+    }
+
+    // Drops an oversized subnegotiation. Whatever has arrived of it goes now and the rest as it
+    // comes in, up to and including the IAC SE. Returns true once it's gone.
+    fn discard_sub(&mut self) -> bool {
+        let from = if self.discarding { 0 } else { 3 };
+        match find_sub_end(self.inbound.as_ref(), from) {
+            Ok(end) => {
+                self.inbound.advance(end + 2);
+                self.discarding = false;
+                true
+            },
+            Err(safe) => {
+                self.inbound.advance(safe);
+                self.discarding = true;
+                false
+            }
+        }
+    }
+
+    // Writes bytes to the socket buffer, deflating them first if MCCP2 is on. Each write is
+    // sync-flushed so the client can act on it immediately.
+    fn write_out(&mut self, data: &[u8], flush: FlushCompress, dst: &mut BytesMut) -> Result<(), io::Error> {
+        self.stats.raw_out += data.len() as u64;
+
+        if let Some(compress) = &mut self.compress {
+            let mut out = Vec::with_capacity(data.len() + 64);
+            let mut input = data;
+            loop {
+                let before_in = compress.total_in();
+                let status = compress.compress_vec(input, &mut out, flush)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                input = &input[(compress.total_in() - before_in) as usize..];
+
+                if status == Status::StreamEnd {
+                    break;
+                }
+                if input.is_empty() && out.len() < out.capacity() {
+                    // Everything was consumed and the output wasn't full, so the flush is done.
+                    break;
+                }
+                out.reserve(out.capacity().max(64));
+            }
+            dst.extend_from_slice(&out);
+            self.stats.wire_out += out.len() as u64;
+        } else {
+            dst.reserve(data.len());
+            dst.put(data);
+            self.stats.wire_out += data.len() as u64;
+        }
+        Ok(())
+    }
+
+    // Ends the MCCP2 stream with a zlib finish so the client knows to go back to plain telnet.
+    fn end_compression(&mut self, dst: &mut BytesMut) -> Result<(), io::Error> {
+        if self.compress.is_some() {
+            self.write_out(&[], FlushCompress::Finish, dst)?;
+            self.compress = None;
         }
+        Ok(())
     }
 }
 
@@ -165,24 +369,20 @@ impl Encoder<TelnetEvent> for TelnetCodec {
     type Error = io::Error;
 
     fn encode(&mut self, item: TelnetEvent, dst: &mut BytesMut) -> Result<(), Self::Error> {
-        let start_encoding = self.encoder.is_none() &&
-            matches!(item, TelnetEvent::SubNegotiate(codes::MCCP2, _));;
+        // IAC SB MCCP2 IAC SE is the last thing sent uncompressed. IAC WONT MCCP2 means we're
+        // turning it off, so the zlib stream must be finished before that goes out in the clear.
+        let start_compression = self.compress.is_none() &&
+            matches!(item, TelnetEvent::SubNegotiate(codes::MCCP2, _));
 
-        let b = Bytes::from(item);
+        if matches!(item, TelnetEvent::Negotiate(codes::WONT, codes::MCCP2)) {
+            self.end_compression(dst)?;
+        }
 
-        if let Some(encoder) = &mut self.encoder {
-            encoder.write_all(b.as_ref());
-            encoder.flush()?;
-            &dst.writer().write_all(encoder.get_mut().get_mut())?;
-            encoder.get_mut().get_mut().clear();
-        } else {
-            dst.reserve(b.len());
-            dst.put(b.as_ref());
-        };
+        let b = Bytes::from(item);
+        self.write_out(b.as_ref(), FlushCompress::Sync, dst)?;
 
-        if start_encoding {
-            self.encoder = Some(ZlibEncoder::new(BytesMut::new().writer(),
-                                                 Compression::best()));
+        if start_compression {
+            self.compress = Some(Compress::new(Compression::new(self.compression_level), true));
         }
 
         Ok(())
@@ -195,28 +395,243 @@ impl Decoder for TelnetCodec {
 
     fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
 
-        if src.len() > self.max_buffer {
-            return Err(Self::Error::from(io::ErrorKind::OutOfMemory));
+        if !src.is_empty() {
+            let data = src.split();
+            self.feed(&data);
         }
 
-        let result = if let Some(decoder) = &mut self.decoder {
-            decoder.write_all(src)?;
-            src.clear();
-            decoder.flush()?;
-            TelnetEvent::parse(&mut decoder.get_mut().get_mut())
-        } else {
-            TelnetEvent::parse(src)
-        };
+        loop {
+            if self.discarding && !self.discard_sub() {
+                return Ok(None);
+            }
 
-        if self.decoder.is_none() {
-            match result {
-                Some(TelnetEvent::SubNegotiate(codes::MCCP3, _)) => {
-                    self.decoder = Some(ZlibDecoder::new(BytesMut::new().writer()));
+            let before = self.inbound.len();
+            let result = TelnetEvent::parse(&mut self.inbound);
+
+            // Subnegotiations are the one thing that can grow without bound, so they're the one
+            // thing with a limit: whether one arrived whole in a single read, or is still waiting
+            // for its IAC SE. Once an oversized one is dropped, whatever came after it is parsed
+            // right away rather than waiting for the next read.
+            if let Some(TelnetEvent::SubNegotiate(op, _)) = &result {
+                if before - self.inbound.len() - 5 > max_subnegotiation(*op) {
+                    continue;
+                }
+            }
+            if result.is_none() && self.inbound.len() > 3 && self.inbound[0] == codes::IAC && self.inbound[1] == codes::SB {
+                // A trailing IAC may be the start of the IAC SE, so it isn't counted yet.
+                let waiting = self.inbound.len() - 3 - usize::from(self.inbound.last() == Some(&codes::IAC));
+                if waiting > max_subnegotiation(self.inbound[2]) {
+                    self.discard_sub();
+                    continue;
+                }
+            }
+
+            // IAC SB MCCP3 IAC SE is the last thing the client sends uncompressed. Anything that
+            // arrived in the same read after it is already part of the zlib stream.
+            if self.mccp3_agreed && self.decompress.is_none() && matches!(result, Some(TelnetEvent::SubNegotiate(codes::MCCP3, _))) {
+                self.decompress = Some(Decompress::new(true));
+                let rest = self.inbound.split();
+                self.stats.raw_in -= rest.len() as u64;
+                self.stats.wire_in -= rest.len() as u64;
+                self.feed(&rest);
+            }
+
+            return Ok(result);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    // Everything parse gets out of the bytes, with runs of Data joined back together since an
+    // escaped IAC comes out as a Data of its own.
+    fn parse_all(bytes: &[u8]) -> Vec<TelnetEvent> {
+        let mut src = BytesMut::from(bytes);
+        let mut out: Vec<TelnetEvent> = Vec::new();
+        while let Some(ev) = TelnetEvent::parse(&mut src) {
+            match (out.last_mut(), ev) {
+                (Some(TelnetEvent::Data(prev)), TelnetEvent::Data(more)) => {
+                    let mut joined = BytesMut::from(prev.as_ref());
+                    joined.extend_from_slice(&more);
+                    *prev = joined.freeze();
                 },
-                _ => {}
+                (_, ev) => out.push(ev)
             }
         }
+        assert!(src.is_empty());
+        out
+    }
+
+    // Bytes with plenty of 0xFF in them.
+    fn iac_heavy(max: usize) -> impl Strategy<Value = Vec<u8>> {
+        prop::collection::vec(prop_oneof![Just(codes::IAC), Just(codes::SE), any::<u8>()], 0..max)
+    }
+
+    proptest! {
+        #[test]
+        fn data_round_trip(data in iac_heavy(512).prop_filter("not empty", |d| !d.is_empty())) {
+            let ev = TelnetEvent::Data(Bytes::from(data));
+            prop_assert_eq!(parse_all(&Bytes::from(ev.clone())), vec![ev]);
+        }
+
+        #[test]
+        fn subnegotiate_round_trip(op in any::<u8>(), data in iac_heavy(512)) {
+            let ev = TelnetEvent::SubNegotiate(op, Bytes::from(data));
+            let mut src = BytesMut::from(Bytes::from(ev.clone()).as_ref());
+            prop_assert_eq!(TelnetEvent::parse(&mut src), Some(ev));
+            prop_assert!(src.is_empty());
+        }
+
+        // NAWS sizes with 255 in them are doubled on the wire and must still fit the NAWS limit,
+        // whichever way the bytes are split across reads.
+        #[test]
+        fn naws_round_trip(
+            width in prop_oneof![Just(255u16), Just(0xFF00), Just(0xFFFF), any::<u16>()],
+            height in prop_oneof![Just(255u16), Just(0x00FF), Just(0xFFFF), any::<u16>()],
+            split in 0usize..16
+        ) {
+            let mut payload = BytesMut::new();
+            payload.put_u16(width);
+            payload.put_u16(height);
+            let ev = TelnetEvent::SubNegotiate(codes::NAWS, payload.freeze());
+            let wire = Bytes::from(ev.clone());
+            let split = split.min(wire.len());
+
+            let mut codec = TelnetCodec::new(9);
+            let mut src = BytesMut::from(&wire[..split]);
+            let mut got = codec.decode(&mut src).unwrap();
+            if got.is_none() {
+                src.extend_from_slice(&wire[split..]);
+                got = codec.decode(&mut src).unwrap();
+            }
+            prop_assert_eq!(got, Some(ev));
+        }
+    }
+
+    #[test]
+    fn oversized_sub_does_not_stall() {
+        let mut oversized = vec![codes::IAC, codes::SB, codes::GMCP];
+        oversized.extend_from_slice(&vec![b'x'; max_subnegotiation(codes::GMCP) + 10]);
+        oversized.extend_from_slice(&[codes::IAC, codes::SE]);
+        let look = TelnetEvent::Data(Bytes::from_static(b"look\r\n"));
+
+        // All in one read.
+        let mut codec = TelnetCodec::new(9);
+        let mut src = BytesMut::from(oversized.as_slice());
+        src.extend_from_slice(b"look\r\n");
+        assert_eq!(codec.decode(&mut src).unwrap(), Some(look.clone()));
+
+        // Split in the middle of the blob, with the command in the same read as its end.
+        let mut codec = TelnetCodec::new(9);
+        let mut src = BytesMut::from(&oversized[..100]);
+        assert_eq!(codec.decode(&mut src).unwrap(), None);
+        src.extend_from_slice(&oversized[100..]);
+        src.extend_from_slice(b"look\r\n");
+        assert_eq!(codec.decode(&mut src).unwrap(), Some(look));
+    }
+
+    fn encode_all(codec: &mut TelnetCodec, events: Vec<TelnetEvent>) -> Vec<BytesMut> {
+        events.into_iter().map(|ev| {
+            let mut dst = BytesMut::new();
+            codec.encode(ev, &mut dst).unwrap();
+            dst
+        }).collect()
+    }
+
+    fn deflate(data: &[u8]) -> Vec<u8> {
+        let mut compress = Compress::new(Compression::default(), true);
+        let mut out = Vec::with_capacity(data.len() + 64);
+        compress.compress_vec(data, &mut out, FlushCompress::Finish).unwrap();
+        out
+    }
+
+    #[test]
+    fn mccp2_round_trip() {
+        let mut codec = TelnetCodec::new(6);
+        let texts: Vec<&[u8]> = vec![b"Welcome!\r\n", b"You see a room.\r\n", &[1, 2, codes::IAC, 3]];
+        let mut events = vec![TelnetEvent::SubNegotiate(codes::MCCP2, Bytes::new())];
+        events.extend(texts.iter().map(|t| TelnetEvent::Data(Bytes::copy_from_slice(t))));
+        events.push(TelnetEvent::Negotiate(codes::WONT, codes::MCCP2));
+        events.push(TelnetEvent::Data(Bytes::from_static(b"plain")));
+        let chunks = encode_all(&mut codec, events);
+
+        assert_eq!(chunks[0].as_ref(), &[codes::IAC, codes::SB, codes::MCCP2, codes::IAC, codes::SE]);
+        assert!(!codec.compressing());
+
+        // Every write is sync-flushed, so each one inflates on its own.
+        let mut inflate = Decompress::new(true);
+        for (chunk, text) in chunks[1..4].iter().zip(texts.iter()) {
+            let mut out = Vec::with_capacity(256);
+            inflate.decompress_vec(chunk, &mut out, FlushDecompress::Sync).unwrap();
+            assert_eq!(out.as_slice(), Bytes::from(TelnetEvent::Data(Bytes::copy_from_slice(text))).as_ref());
+        }
+
+        // WONT MCCP2 finishes the zlib stream first and then goes out in the clear.
+        let last = &chunks[4];
+        let mut out = Vec::with_capacity(256);
+        let before = inflate.total_in();
+        let status = inflate.decompress_vec(last, &mut out, FlushDecompress::Finish).unwrap();
+        assert_eq!(status, Status::StreamEnd);
+        assert!(out.is_empty());
+        let used = (inflate.total_in() - before) as usize;
+        assert_eq!(&last[used..], &[codes::IAC, codes::WONT, codes::MCCP2]);
+        assert_eq!(chunks[5].as_ref(), b"plain");
+    }
+
+    #[test]
+    fn mccp3_same_read() {
+        let mut codec = TelnetCodec::new(9);
+        codec.set_mccp3_agreed(true);
+        let mut src = BytesMut::new();
+        src.extend_from_slice(&[codes::IAC, codes::SB, codes::MCCP3, codes::IAC, codes::SE]);
+        src.extend_from_slice(&deflate(b"look\r\n"));
+        src.extend_from_slice(b"after");
+
+        assert_eq!(codec.decode(&mut src).unwrap(), Some(TelnetEvent::SubNegotiate(codes::MCCP3, Bytes::new())));
+        assert!(src.is_empty());
+        assert_eq!(codec.decode(&mut src).unwrap(), Some(TelnetEvent::Data(Bytes::from_static(b"look\r\nafter"))));
+        // The client's stream ended, so what followed it was plain telnet.
+        assert!(!codec.decompressing());
+    }
+
+    #[test]
+    fn unsolicited_mccp3() {
+        let mut codec = TelnetCodec::new(9);
+        let mut src = BytesMut::new();
+        src.extend_from_slice(&[codes::IAC, codes::SB, codes::MCCP3, codes::IAC, codes::SE]);
+        src.extend_from_slice(b"look\r\n");
+
+        assert_eq!(codec.decode(&mut src).unwrap(), Some(TelnetEvent::SubNegotiate(codes::MCCP3, Bytes::new())));
+        assert!(!codec.decompressing());
+        assert_eq!(codec.decode(&mut src).unwrap(), Some(TelnetEvent::Data(Bytes::from_static(b"look\r\n"))));
+    }
+
+    #[test]
+    fn compression_stats() {
+        let mut codec = TelnetCodec::new(9);
+        encode_all(&mut codec, vec![TelnetEvent::Data(Bytes::from_static(b"0123456789"))]);
+        assert_eq!((codec.stats().raw_out, codec.stats().wire_out), (10, 10));
+
+        let text = Bytes::from(vec![b'a'; 1000]);
+        let chunks = encode_all(&mut codec, vec![
+            TelnetEvent::SubNegotiate(codes::MCCP2, Bytes::new()),
+            TelnetEvent::Data(text.clone())
+        ]);
+        let wire: usize = chunks.iter().map(|c| c.len()).sum();
+        assert_eq!(codec.stats().raw_out, 10 + 5 + 1000);
+        assert_eq!(codec.stats().wire_out, 10 + wire as u64);
+        assert!(codec.stats().wire_out < codec.stats().raw_out);
 
-        Ok(result)
+        let compressed = deflate(&text);
+        codec.set_mccp3_agreed(true);
+        let mut src = BytesMut::new();
+        src.extend_from_slice(&[codes::IAC, codes::SB, codes::MCCP3, codes::IAC, codes::SE]);
+        src.extend_from_slice(&compressed);
+        while codec.decode(&mut src).unwrap().is_some() {}
+        assert_eq!(codec.stats().wire_in, 5 + compressed.len() as u64);
+        assert_eq!(codec.stats().raw_in, 5 + 1000);
     }
 }
@@ -1,4 +1,5 @@
 pub const NULL: u8 = 0;
+pub const ECHO: u8 = 1;
 pub const BEL: u8 = 7;
 pub const CR: u8 = 13;
 pub const LF: u8 = 10;
@@ -19,19 +20,24 @@ pub const IAC: u8 = 255;
 
 // The following are special MUD specific protocols.
 
-// MNES: Mud New-Environ standard
+// MNES: Mud New-Environ standard. This is TELOPT NEW-ENVIRON.
 pub const MNES: u8 = 39;
 
 // MUD eXtension Protocol
-// NOTE: Disabled due to too many issues with it.
 pub const MXP: u8 = 91;
 
+// CHARSET negotiation (RFC 2066)
+pub const CHARSET: u8 = 42;
+
+// START_TLS (draft-altman-telnet-starttls). FOLLOWS is its only sub-negotiation command.
+pub const START_TLS: u8 = 46;
+pub const START_TLS_FOLLOWS: u8 = 1;
+
 // Mud Server Status Protocol
 pub const MSSP: u8 = 70;
 
 // Compression
 // pub const MCCP1: u8 = 85 - this is deprecrated
-// NOTE: MCCP2 and MCCP3 is currently disabled.
 pub const MCCP2: u8 = 86;
 pub const MCCP3: u8 = 87;
 
@@ -43,3 +49,15 @@ pub const MSDP: u8 = 69;
 
 // MTTS - Terminal Type
 pub const MTTS: u8 = 24;
+
+// MSSP sub-negotiation tokens.
+pub const MSSP_VAR: u8 = 1;
+pub const MSSP_VAL: u8 = 2;
+
+// MSDP sub-negotiation tokens.
+pub const MSDP_VAR: u8 = 1;
+pub const MSDP_VAL: u8 = 2;
+pub const MSDP_TABLE_OPEN: u8 = 3;
+pub const MSDP_TABLE_CLOSE: u8 = 4;
+pub const MSDP_ARRAY_OPEN: u8 = 5;
+pub const MSDP_ARRAY_CLOSE: u8 = 6;
@@ -0,0 +1,93 @@
+use std::collections::HashMap;
+
+use bytes::Bytes;
+
+use serde_json::Value as JsonValue;
+
+use crate::protocols::MudData;
+
+// GMCP data is sent via IAC SB <GMCP> <package>[ <json>] IAC SE. The json part is optional and
+// must be separated from the package name by a space if present.
+//
+// Outbound, we always send the body as [args, kwargs] so the client sees one consistent shape.
+// For example: IAC SB GMCP Room.Info [[], {"name": "The Hall of Limbo", "id": 50}] IAC SE
+//
+// Inbound, clients send whatever they like, so decoding accepts that same [args, kwargs] pair
+// as well as a plain object (kwargs), a plain array (args) or a single scalar (one arg).
+
+// Set in kwargs when a client sends a GMCP body that isn't valid JSON. The raw body is left in
+// args so the game can still log it.
+pub const GMCP_ERROR_KEY: &str = "gmcp_error";
+
+pub fn encode(d: &MudData) -> Bytes {
+    let json_data = JsonValue::Array(vec![
+        JsonValue::Array(d.args.clone()),
+        JsonValue::Object(d.kwargs.clone().into_iter().collect())
+    ]);
+    Bytes::from(format!("{} {}", d.cmd, json_data))
+}
+
+pub fn decode(data: &[u8]) -> Option<MudData> {
+    let s = String::from_utf8_lossy(data);
+    let s = s.trim();
+    if s.is_empty() {
+        return None;
+    }
+
+    let (cmd, body) = match s.split_once(' ') {
+        Some((cmd, body)) => (cmd, body.trim()),
+        None => (s, "")
+    };
+
+    let mut d = MudData {
+        cmd: cmd.to_string(),
+        args: Vec::new(),
+        kwargs: HashMap::new()
+    };
+
+    if body.is_empty() {
+        return Some(d);
+    }
+
+    match serde_json::from_str::<JsonValue>(body) {
+        Ok(JsonValue::Array(mut arr)) => {
+            if arr.len() == 2 && arr[0].is_array() && arr[1].is_object() {
+                // This is our own [args, kwargs] convention.
+                if let (JsonValue::Object(kwargs), JsonValue::Array(args)) = (arr.remove(1), arr.remove(0)) {
+                    d.args = args;
+                    d.kwargs = kwargs.into_iter().collect();
+                }
+            } else {
+                d.args = arr;
+            }
+        },
+        Ok(JsonValue::Object(obj)) => {
+            d.kwargs = obj.into_iter().collect();
+        },
+        Ok(other) => {
+            d.args.push(other);
+        },
+        Err(e) => {
+            d.args.push(JsonValue::String(body.to_string()));
+            d.kwargs.insert(GMCP_ERROR_KEY.to_string(), JsonValue::String(e.to_string()));
+        }
+    }
+
+    Some(d)
+}
+
+// Core.Supports.* carries a list of "Package.Name <version>" strings. The version is optional
+// and defaults to 1.
+pub fn parse_supports(args: &[JsonValue]) -> Vec<(String, u32)> {
+    let mut out = Vec::new();
+    for arg in args {
+        if let Some(s) = arg.as_str() {
+            let mut parts = s.split_whitespace();
+            if let Some(name) = parts.next() {
+                let version = parts.next().and_then(|v| v.parse().ok()).unwrap_or(1);
+                out.push((name.to_string(), version));
+            }
+        }
+    }
+    out
+}
@@ -0,0 +1,502 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use crate::{
+    config::Config,
+    protocols::{
+        telnet::{
+            charset::{self, Charset},
+            codec::TelnetEvent,
+            codes as tc,
+            gmcp,
+            mnes,
+            msdp,
+            mssp,
+            options::{OptionContext, OptionPolicy, TelnetOptionHandler, TelnetOptionRegistry},
+            quirks
+        },
+        mxp::MxpMode,
+        {Color, ProtocolCapabilities}
+    },
+    MSSP
+};
+
+// The telnet options the portal supports out of the box.
+pub fn register_defaults(registry: &mut TelnetOptionRegistry) {
+    registry.register(tc::SGA, || Box::new(Flag::new(OptionPolicy::local(), |local, _, caps| caps.sga = local)));
+    // ECHO is never offered up front, and never agreed to when the client asks: it hides what
+    // the player types. The game turns it on for password entry and character mode.
+    let echo = OptionPolicy { game_local: true, ..Default::default() };
+    registry.register(tc::ECHO, move || Box::new(Flag::new(echo, |local, _, caps| caps.secret_input = local)));
+    registry.register(tc::TELOPT_EOR, || Box::new(Flag::new(OptionPolicy::local(), |_, _, _| {})));
+    registry.register(tc::LINEMODE, || Box::new(Flag::new(OptionPolicy::remote(), |_, remote, caps| caps.linemode = remote)));
+    registry.register(tc::MCCP3, || Box::new(Mccp3));
+    registry.register(tc::MCCP2, || Box::new(Mccp2));
+    registry.register(tc::MXP, || Box::new(Mxp));
+    registry.register(tc::MSSP, || Box::new(Mssp));
+    registry.register(tc::GMCP, || Box::new(Gmcp));
+    registry.register(tc::MSDP, || Box::new(Msdp));
+    registry.register(tc::CHARSET, || Box::new(CharsetOption));
+    registry.register(tc::NAWS, || Box::new(Naws));
+    registry.register(tc::MTTS, || Box::new(Ttype::default()));
+    registry.register(tc::MNES, || Box::new(Mnes));
+    // Only offered when the connection has a TlsAcceptor to upgrade with.
+    registry.register(tc::START_TLS, || Box::new(StartTlsOption));
+}
+
+// An option that only switches capability flags.
+pub struct Flag {
+    policy: OptionPolicy,
+    apply: fn(bool, bool, &mut ProtocolCapabilities)
+}
+
+impl Flag {
+    pub fn new(policy: OptionPolicy, apply: fn(bool, bool, &mut ProtocolCapabilities)) -> Self {
+        Self { policy, apply }
+    }
+}
+
+impl TelnetOptionHandler for Flag {
+    fn policy(&self) -> OptionPolicy {
+        self.policy
+    }
+
+    fn capabilities(&self, local: bool, remote: bool, caps: &mut ProtocolCapabilities) {
+        (self.apply)(local, remote, caps);
+    }
+}
+
+pub struct Mccp2;
+
+impl TelnetOptionHandler for Mccp2 {
+    fn policy(&self) -> OptionPolicy {
+        OptionPolicy::local()
+    }
+
+    // The codec starts compressing right after IAC SB MCCP2 IAC SE, and finishes the zlib stream
+    // before the IAC WONT MCCP2 that turns it off.
+    fn enable_local(&mut self, ctx: &mut OptionContext) {
+        ctx.send(TelnetEvent::SubNegotiate(tc::MCCP2, Bytes::new()));
+    }
+
+    fn capabilities(&self, local: bool, _remote: bool, caps: &mut ProtocolCapabilities) {
+        caps.mccp2 = local;
+    }
+}
+
+pub struct Mccp3;
+
+impl TelnetOptionHandler for Mccp3 {
+    fn policy(&self) -> OptionPolicy {
+        OptionPolicy::local()
+    }
+
+    // The client starts its zlib stream with IAC SB MCCP3 IAC SE, which the codec only honours
+    // once we've agreed to MCCP3.
+    fn enable_local(&mut self, ctx: &mut OptionContext) {
+        ctx.accept_mccp3(true);
+    }
+
+    fn disable_local(&mut self, ctx: &mut OptionContext) {
+        ctx.accept_mccp3(false);
+    }
+
+    fn capabilities(&self, local: bool, _remote: bool, caps: &mut ProtocolCapabilities) {
+        caps.mccp3 = local;
+    }
+}
+
+pub struct Mxp;
+
+impl TelnetOptionHandler for Mxp {
+    fn policy(&self) -> OptionPolicy {
+        OptionPolicy::local()
+    }
+
+    // MXP starts with an empty sub-negotiation. We then lock the client into locked mode so that
+    // ordinary text is never parsed as markup. Only text the game marks as MXP is sent in secure
+    // or open mode.
+    fn enable_local(&mut self, ctx: &mut OptionContext) {
+        ctx.send(TelnetEvent::SubNegotiate(tc::MXP, Bytes::new()));
+        ctx.send(TelnetEvent::Data(Bytes::from(MxpMode::LockLocked.escape())));
+    }
+
+    fn capabilities(&self, local: bool, _remote: bool, caps: &mut ProtocolCapabilities) {
+        caps.mxp = local;
+    }
+}
+
+pub struct Mssp;
+
+impl TelnetOptionHandler for Mssp {
+    fn policy(&self) -> OptionPolicy {
+        OptionPolicy::local()
+    }
+
+    // Whoever says DO MSSP gets the data at once, game or no game. The protocol keeps clients
+    // that do nothing else away from the game, see looks_like_crawler.
+    fn enable_local(&mut self, ctx: &mut OptionContext) {
+        let vars = MSSP.lock().unwrap().variables(&ctx.config.mssp);
+        ctx.send(TelnetEvent::SubNegotiate(tc::MSSP, mssp::encode(&vars)));
+    }
+
+    fn capabilities(&self, local: bool, _remote: bool, caps: &mut ProtocolCapabilities) {
+        caps.mssp = local;
+    }
+}
+
+pub struct Gmcp;
+
+impl TelnetOptionHandler for Gmcp {
+    fn policy(&self) -> OptionPolicy {
+        OptionPolicy::local()
+    }
+
+    // The Core package is about the GMCP session itself, so the portal answers it rather than
+    // the game. Everything else is passed along.
+    fn subnegotiate(&mut self, ctx: &mut OptionContext, data: Bytes) {
+        let d = match gmcp::decode(&data) {
+            Some(d) => d,
+            None => return
+        };
+
+        match d.cmd.to_lowercase().as_str() {
+            "core.hello" => {
+                if let Some(client) = d.kwargs.get("client").and_then(|v| v.as_str()) {
+                    ctx.capabilities.client_name = client.to_uppercase();
+                }
+                if let Some(version) = d.kwargs.get("version").and_then(|v| v.as_str()) {
+                    ctx.capabilities.client_version = version.to_string();
+                }
+                ctx.capabilities_changed();
+            },
+            "core.supports.set" => {
+                ctx.capabilities.gmcp_supports = gmcp::parse_supports(&d.args).into_iter().collect();
+                ctx.capabilities_changed();
+            },
+            "core.supports.add" => {
+                ctx.capabilities.gmcp_supports.extend(gmcp::parse_supports(&d.args));
+                ctx.capabilities_changed();
+            },
+            "core.supports.remove" => {
+                for (name, _) in gmcp::parse_supports(&d.args) {
+                    ctx.capabilities.gmcp_supports.remove(&name);
+                }
+                ctx.capabilities_changed();
+            },
+            "core.ping" => {
+                ctx.send(TelnetEvent::SubNegotiate(tc::GMCP, Bytes::from_static(b"Core.Ping")));
+            },
+            _ => ctx.to_game(d)
+        }
+    }
+
+    fn capabilities(&self, local: bool, _remote: bool, caps: &mut ProtocolCapabilities) {
+        caps.gmcp = local;
+    }
+}
+
+pub struct Msdp;
+
+impl TelnetOptionHandler for Msdp {
+    fn policy(&self) -> OptionPolicy {
+        OptionPolicy::local()
+    }
+
+    fn subnegotiate(&mut self, ctx: &mut OptionContext, data: Bytes) {
+        for d in msdp::decode(&data) {
+            ctx.to_game(d);
+        }
+    }
+
+    fn capabilities(&self, local: bool, _remote: bool, caps: &mut ProtocolCapabilities) {
+        caps.msdp = local;
+    }
+}
+
+// The encodings from the config, in order of preference, leaving out any we don't know.
+pub fn offered_charsets(config: &Config) -> Vec<Charset> {
+    config.telnet.charsets.iter().filter_map(|n| Charset::from_name(n)).collect()
+}
+
+pub struct CharsetOption;
+
+impl TelnetOptionHandler for CharsetOption {
+    // Either side may start RFC 2066 negotiation, so both are allowed. We only offer ours.
+    fn policy(&self) -> OptionPolicy {
+        OptionPolicy { allow_local: true, allow_remote: true, start_local: true, ..Default::default() }
+    }
+
+    fn enable_local(&mut self, ctx: &mut OptionContext) {
+        let offered = offered_charsets(ctx.config);
+        ctx.send(TelnetEvent::SubNegotiate(tc::CHARSET, charset::request(&offered)));
+    }
+
+    fn subnegotiate(&mut self, ctx: &mut OptionContext, data: Bytes) {
+        let (&command, rest) = match data.split_first() {
+            Some(v) => v,
+            None => return
+        };
+
+        match command {
+            charset::ACCEPTED => {
+                // The client picked one of the charsets from our REQUEST.
+                if let Some(c) = Charset::from_name(&String::from_utf8_lossy(rest)) {
+                    ctx.set_charset(c);
+                    ctx.capabilities_changed();
+                }
+            },
+            charset::REQUEST => {
+                // The client is offering charsets to us. Take the first one we can handle.
+                let offered = offered_charsets(ctx.config);
+                let chosen = charset::parse_request(rest).iter()
+                    .filter_map(|n| Charset::from_name(n))
+                    .find(|c| offered.contains(c));
+                let mut reply = BytesMut::new();
+                match chosen {
+                    Some(c) => {
+                        reply.put_u8(charset::ACCEPTED);
+                        reply.put(c.name().as_bytes());
+                        ctx.set_charset(c);
+                    },
+                    None => reply.put_u8(charset::REJECTED)
+                }
+                ctx.send(TelnetEvent::SubNegotiate(tc::CHARSET, reply.freeze()));
+                ctx.capabilities_changed();
+            },
+            _ => {
+                // REJECTED leaves us on the default. Translation tables aren't supported.
+            }
+        }
+    }
+}
+
+pub struct Naws;
+
+impl TelnetOptionHandler for Naws {
+    fn policy(&self) -> OptionPolicy {
+        OptionPolicy::remote()
+    }
+
+    fn disable_remote(&mut self, ctx: &mut OptionContext) {
+        ctx.capabilities.width = 78;
+        ctx.capabilities.height = 24;
+    }
+
+    fn subnegotiate(&mut self, ctx: &mut OptionContext, mut data: Bytes) {
+        if data.len() < 4 {
+            return;
+        }
+        let width = data.get_u16();
+        let height = data.get_u16();
+        if width != ctx.capabilities.width || height != ctx.capabilities.height {
+            ctx.capabilities.width = width;
+            ctx.capabilities.height = height;
+            ctx.capabilities_changed();
+        }
+    }
+
+    fn capabilities(&self, _local: bool, remote: bool, caps: &mut ProtocolCapabilities) {
+        caps.naws = remote;
+    }
+}
+
+// MTTS is a bitfield of client capabilities, reported through TTYPE or the MNES MTTS variable.
+pub fn apply_mtts(caps: &mut ProtocolCapabilities, mtts: usize) {
+    if (1 & mtts) == 1 && (caps.color.clone() as i32) < Color::Standard as i32 {
+        caps.color = Color::Standard;
+    }
+
+    if (2 & mtts) == 2 {
+        caps.vt100 = true;
+    }
+    if (4 & mtts) == 4 {
+        caps.utf8 = true;
+    }
+    if (8 & mtts) == 8 && (caps.color.clone() as i32) < Color::Xterm256 as i32 {
+        caps.color = Color::Xterm256;
+    }
+    if (16 & mtts) == 16 {
+        caps.mouse_tracking = true;
+    }
+    if (32 & mtts) == 32 {
+        caps.osc_color_palette = true;
+    }
+    if (64 & mtts) == 64 {
+        caps.screen_reader = true;
+    }
+    if (128 & mtts) == 128 {
+        caps.proxy = true;
+    }
+    if (256 & mtts) == 256 && (caps.color.clone() as i32) < Color::TrueColor as i32 {
+        caps.color = Color::TrueColor;
+    }
+    if (512 & mtts) == 512 {
+        caps.mnes = true;
+    }
+}
+
+// Terminal type. RFC 1091 has the client step through its list of terminal types, one per
+// request, and repeat the last one once the list runs out. MTTS clients give their name first and
+// end with "MTTS <bitfield>". We keep asking until a value comes back a second time: either the
+// repeat at the end, or the first one again from a client that wraps around instead.
+#[derive(Default)]
+pub struct Ttype {
+    types: Vec<String>,
+    // Waiting for a reply.
+    pending: bool
+}
+
+// In case a client never repeats itself.
+const MAX_TERMINAL_TYPES: usize = 16;
+
+impl Ttype {
+    fn request(&mut self, ctx: &mut OptionContext) {
+        self.pending = true;
+        ctx.send(TelnetEvent::SubNegotiate(tc::MTTS, Bytes::from_static(&[1])));
+    }
+
+    fn receive_name(&mut self, ctx: &mut OptionContext, data: &str) {
+        // The first TTYPE receives the name of the client.
+        // version might also be in here as a second word.
+        let caps = &mut *ctx.capabilities;
+        match data.split_once(' ') {
+            Some((name, version)) => {
+                caps.client_name = name.to_string();
+                caps.client_version = version.to_string();
+            },
+            None => caps.client_name = data.to_string()
+        }
+    }
+
+    fn receive_mtts(&mut self, ctx: &mut OptionContext, data: &str) {
+        let mtts: usize = match data.strip_prefix("MTTS ") {
+            Some(value) => value.parse().unwrap_or(0),
+            None => return
+        };
+        if mtts > 0 {
+            apply_mtts(ctx.capabilities, mtts);
+        }
+    }
+}
+
+impl TelnetOptionHandler for Ttype {
+    fn policy(&self) -> OptionPolicy {
+        OptionPolicy::remote()
+    }
+
+    fn enable_remote(&mut self, ctx: &mut OptionContext) {
+        self.request(ctx);
+    }
+
+    fn disable_remote(&mut self, _ctx: &mut OptionContext) {
+        self.pending = false;
+    }
+
+    fn subnegotiate(&mut self, ctx: &mut OptionContext, mut data: Bytes) {
+        if data.len() < 2 || !self.pending || data[0] != 0 {
+            return;
+        }
+        data.advance(1);
+
+        let upper = match String::from_utf8(data.to_vec()) {
+            Ok(s) => s.trim().to_uppercase(),
+            Err(_) => return
+        };
+
+        if self.types.contains(&upper) {
+            // The list is done.
+            self.pending = false;
+            ctx.capabilities_changed();
+            return;
+        }
+
+        if self.types.is_empty() {
+            self.receive_name(ctx, &upper);
+        }
+        self.receive_mtts(ctx, &upper);
+        self.types.push(upper);
+        ctx.capabilities.terminal_types = self.types.clone();
+
+        // Now that the name, version and terminal types are known, we can deduce capabilities.
+        quirks::apply_all(&ctx.config.telnet.quirks, ctx.capabilities);
+
+        if self.types.len() < MAX_TERMINAL_TYPES {
+            self.request(ctx);
+        } else {
+            self.pending = false;
+        }
+        ctx.capabilities_changed();
+    }
+
+    fn capabilities(&self, _local: bool, remote: bool, caps: &mut ProtocolCapabilities) {
+        caps.ttype = remote;
+    }
+
+    fn negotiating(&self) -> bool {
+        self.pending
+    }
+}
+
+pub struct Mnes;
+
+impl TelnetOptionHandler for Mnes {
+    fn policy(&self) -> OptionPolicy {
+        OptionPolicy::remote()
+    }
+
+    fn enable_remote(&mut self, ctx: &mut OptionContext) {
+        ctx.send(TelnetEvent::SubNegotiate(tc::MNES, mnes::request()));
+    }
+
+    // Both the IS reply to our SEND and any later INFO updates land here.
+    fn subnegotiate(&mut self, ctx: &mut OptionContext, data: Bytes) {
+        let vars = match mnes::decode(&data) {
+            Some((_, vars)) => vars,
+            None => return
+        };
+
+        for (name, value) in vars {
+            match name.as_str() {
+                "CLIENT_NAME" => ctx.capabilities.client_name = value.to_uppercase(),
+                "CLIENT_VERSION" => ctx.capabilities.client_version = value.clone(),
+                "CHARSET" => {
+                    if let Some(c) = Charset::from_name(&value) {
+                        ctx.set_charset(c);
+                    }
+                },
+                "MTTS" => {
+                    if let Ok(mtts) = value.parse() {
+                        apply_mtts(ctx.capabilities, mtts);
+                    }
+                },
+                _ => {}
+            }
+            ctx.capabilities.environ.insert(name, value);
+        }
+
+        ctx.capabilities_changed();
+    }
+
+    fn capabilities(&self, _local: bool, remote: bool, caps: &mut ProtocolCapabilities) {
+        caps.mnes = remote;
+    }
+}
+
+pub struct StartTlsOption;
+
+impl TelnetOptionHandler for StartTlsOption {
+    fn policy(&self) -> OptionPolicy {
+        OptionPolicy::remote()
+    }
+
+    // We tell the client TLS follows. It answers the same way and then sends its ClientHello.
+    fn enable_remote(&mut self, ctx: &mut OptionContext) {
+        ctx.send(TelnetEvent::SubNegotiate(tc::START_TLS, Bytes::from_static(&[tc::START_TLS_FOLLOWS])));
+    }
+
+    fn subnegotiate(&mut self, ctx: &mut OptionContext, data: Bytes) {
+        if data.as_ref() == [tc::START_TLS_FOLLOWS] {
+            ctx.start_tls();
+        }
+    }
+}
@@ -0,0 +1,235 @@
+use bytes::{Bytes, BytesMut};
+
+use crate::config::LineEnding;
+
+use super::codes;
+
+// Turns the raw bytes a client types into command lines, before charset decoding. RFC 854 says a
+// line ends with CR LF and a lone carriage return is sent as CR NUL, but real clients also send
+// bare CR or bare LF. Which of these count as a line ending is configurable. Backspace and DEL
+// edit the pending line, for clients that send keystrokes as they're typed. Everything else
+// below 0x20, and any escape sequences a player pastes or injects, are dropped.
+
+const BS: u8 = 8;
+const TAB: u8 = 9;
+const ESC: u8 = 27;
+const DEL: u8 = 127;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EscapeState {
+    None,
+    // Just saw ESC.
+    Esc,
+    // Inside ESC [ ..., waiting for the final byte.
+    Csi,
+    // Inside ESC ] ..., waiting for BEL or ESC \.
+    Osc,
+    // Saw ESC inside an OSC string.
+    OscEsc
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InputLine {
+    Line(Bytes),
+    // A line went over the maximum length and was thrown away.
+    TooLong
+}
+
+pub struct LineNormalizer {
+    endings: Vec<LineEnding>,
+    // In bytes. 0 means no limit.
+    max_length: usize,
+    line: BytesMut,
+    // The last byte was a CR, so an LF or NUL after it belongs to the same line ending.
+    after_cr: bool,
+    escape: EscapeState,
+    // The pending line went over max_length. The rest of it is ignored.
+    overflow: bool
+}
+
+impl LineNormalizer {
+    pub fn new(endings: Vec<LineEnding>, max_length: usize) -> Self {
+        Self {
+            endings,
+            max_length,
+            line: BytesMut::with_capacity(256),
+            after_cr: false,
+            escape: EscapeState::None,
+            overflow: false
+        }
+    }
+
+    fn ends_with(&self, ending: LineEnding) -> bool {
+        self.endings.contains(&ending)
+    }
+
+    fn finish_line(&mut self, out: &mut Vec<InputLine>) {
+        if self.overflow {
+            out.push(InputLine::TooLong);
+        } else {
+            out.push(InputLine::Line(self.line.split().freeze()));
+        }
+        self.line.clear();
+        self.overflow = false;
+        self.escape = EscapeState::None;
+    }
+
+    // Removes the last character of the pending line. For UTF-8 that's a lead byte and any
+    // continuation bytes after it; the other charsets are one byte per character.
+    fn erase(&mut self, utf8: bool) {
+        while let Some(b) = self.line.last().copied() {
+            self.line.truncate(self.line.len() - 1);
+            if !utf8 || (b & 0xC0) != 0x80 {
+                break;
+            }
+        }
+    }
+
+    fn push(&mut self, b: u8) {
+        if self.overflow {
+            return;
+        }
+        if self.max_length > 0 && self.line.len() >= self.max_length {
+            self.overflow = true;
+            self.line.clear();
+            return;
+        }
+        self.line.extend_from_slice(&[b]);
+    }
+
+    // Returns true if b was part of an escape sequence and should go no further.
+    fn skip_escape(&mut self, b: u8) -> bool {
+        self.escape = match (self.escape, b) {
+            (EscapeState::None, ESC) => EscapeState::Esc,
+            (EscapeState::None, _) => return false,
+            (EscapeState::Esc, b'[') => EscapeState::Csi,
+            (EscapeState::Esc, b']') => EscapeState::Osc,
+            // Any other ESC x is a two-byte sequence.
+            (EscapeState::Esc, _) => EscapeState::None,
+            (EscapeState::Csi, 0x40..=0x7e) => EscapeState::None,
+            (EscapeState::Csi, _) => EscapeState::Csi,
+            (EscapeState::Osc, codes::BEL) => EscapeState::None,
+            (EscapeState::Osc, ESC) => EscapeState::OscEsc,
+            (EscapeState::Osc, _) => EscapeState::Osc,
+            (EscapeState::OscEsc, _) => EscapeState::None
+        };
+        true
+    }
+
+    // Feeds more client bytes in and returns the lines they complete. utf8 says whether the
+    // client's charset is UTF-8, so backspace erases whole characters.
+    pub fn feed(&mut self, data: &[u8], utf8: bool) -> Vec<InputLine> {
+        let mut out = Vec::new();
+
+        for &b in data {
+            let after_cr = std::mem::replace(&mut self.after_cr, b == codes::CR);
+
+            match b {
+                codes::CR => {
+                    if self.ends_with(LineEnding::Cr) {
+                        self.finish_line(&mut out);
+                    }
+                },
+                codes::LF => {
+                    if after_cr {
+                        // A bare-CR ending already finished the line.
+                        if !self.ends_with(LineEnding::Cr) && (self.ends_with(LineEnding::CrLf) || self.ends_with(LineEnding::Lf)) {
+                            self.finish_line(&mut out);
+                        }
+                    } else if self.ends_with(LineEnding::Lf) {
+                        self.finish_line(&mut out);
+                    }
+                },
+                codes::NULL => {
+                    if after_cr && !self.ends_with(LineEnding::Cr) && self.ends_with(LineEnding::CrNul) {
+                        self.finish_line(&mut out);
+                    }
+                },
+                _ if self.skip_escape(b) => {},
+                BS | DEL => self.erase(utf8),
+                // Tabs separate words as often as spaces do, so they're kept as one.
+                TAB => self.push(b' '),
+                _ if b < 0x20 => {},
+                _ => self.push(b)
+            }
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use LineEnding::*;
+
+    fn all() -> LineNormalizer {
+        LineNormalizer::new(vec![CrLf, CrNul, Cr, Lf], 0)
+    }
+
+    fn lines(out: Vec<InputLine>) -> Vec<String> {
+        out.into_iter().map(|l| match l {
+            InputLine::Line(b) => String::from_utf8_lossy(&b).to_string(),
+            InputLine::TooLong => "<too long>".to_string()
+        }).collect()
+    }
+
+    #[test]
+    fn endings() {
+        let mut input = all();
+        assert_eq!(lines(input.feed(b"look\r\nsay hi\r\0north\rsouth\neast", false)), ["look", "say hi", "north", "south"]);
+        assert_eq!(lines(input.feed(b"\r\n", false)), ["east"]);
+
+        // A CR LF split across reads is still one line ending.
+        let mut input = all();
+        assert_eq!(lines(input.feed(b"look\r", false)), ["look"]);
+        assert_eq!(lines(input.feed(b"\n\r\n", false)), [""]);
+    }
+
+    #[test]
+    fn configured_endings() {
+        let mut input = LineNormalizer::new(vec![CrLf], 0);
+        assert_eq!(lines(input.feed(b"one\rtwo\nthree\r\n", false)), ["onetwothree"]);
+
+        let mut input = LineNormalizer::new(vec![CrNul], 0);
+        assert_eq!(lines(input.feed(b"one\r\ntwo\r\0", false)), ["onetwo"]);
+
+        // Without bare CR, CR LF and CR NUL each end a line once.
+        let mut input = LineNormalizer::new(vec![CrLf, CrNul, Lf], 0);
+        assert_eq!(lines(input.feed(b"one\r\ntwo\r\0three\n", false)), ["one", "two", "three"]);
+    }
+
+    #[test]
+    fn backspace() {
+        let mut input = all();
+        assert_eq!(lines(input.feed(b"lookk\x08 x\x7f\x7f\r\n", false)), ["look"]);
+        // Erasing past the start of the line does nothing.
+        assert_eq!(lines(input.feed(b"\x08\x08n\r\n", false)), ["n"]);
+        // In UTF-8 one backspace removes the whole character.
+        assert_eq!(lines(input.feed("caf\u{e9}\x08e\r\n".as_bytes(), true)), ["cafe"]);
+        // In other charsets it's one byte.
+        assert_eq!(lines(input.feed(b"caf\xc3\xa9\x08\r\n", false)), ["caf\u{fffd}"]);
+    }
+
+    #[test]
+    fn control_and_escapes() {
+        let mut input = all();
+        assert_eq!(lines(input.feed(b"say\thi\x07\x01\r\n", false)), ["say hi"]);
+        assert_eq!(lines(input.feed(b"say \x1b[31mred\x1b[0m\r\n", false)), ["say red"]);
+        assert_eq!(lines(input.feed(b"x\x1b]0;title\x07y\x1b]2;t\x1b\\z\r\n", false)), ["xyz"]);
+        // ESC followed by one more byte, and a CSI split across reads.
+        assert_eq!(lines(input.feed(b"a\x1bcb\x1b[", false)), Vec::<String>::new());
+        assert_eq!(lines(input.feed(b"1;2Hc\r\n", false)), ["abc"]);
+        // A line ending cuts off an unfinished sequence.
+        assert_eq!(lines(input.feed(b"\x1b[1\r\nok\r\n", false)), ["", "ok"]);
+    }
+
+    #[test]
+    fn too_long() {
+        let mut input = LineNormalizer::new(vec![CrLf], 5);
+        assert_eq!(lines(input.feed(b"12345\r\n123456\r\n", false)), ["12345", "<too long>"]);
+        // The rest of an overlong line is ignored across reads, and the next line is fine.
+        assert_eq!(lines(input.feed(b"1234567", false)), Vec::<String>::new());
+        assert_eq!(lines(input.feed(b"89\r\nlook\r\n", false)), ["<too long>", "look"]);
+    }
+}
@@ -0,0 +1,379 @@
+use bytes::{Bytes, BytesMut};
+
+use super::codes;
+
+// Turns what a client sends in character mode into keystrokes. Printable characters come out as
+// text, still in the client's charset. Control characters and the ANSI escape sequences terminals
+// send for arrows, function keys and the like come out as key names: "enter", "up", "f5",
+// "ctrl-c", "alt-x", "shift-tab", "ctrl-left"... Mouse reports (SGR and the older X10 form) are
+// only decoded for clients with mouse tracking. Sequences we don't know are dropped.
+
+const BS: u8 = 8;
+const TAB: u8 = 9;
+const ESC: u8 = 27;
+const DEL: u8 = 127;
+
+// No key sequence comes near this. Anything longer is garbage and gets dropped.
+const MAX_SEQUENCE: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum KeyState {
+    Ground,
+    // Just saw ESC.
+    Esc,
+    // ESC [ ..., collecting parameters until the final byte.
+    Csi,
+    // ESC O, one more byte names the key.
+    Ss3,
+    // ESC [ M, then three bytes of X10 mouse report.
+    X10Mouse
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MouseReport {
+    // left, middle, right, wheel-up, wheel-down, wheel-left, wheel-right, or none for movement
+    // with no button held.
+    pub button: &'static str,
+    // press, release or move.
+    pub action: &'static str,
+    // 1-based screen cell.
+    pub x: u16,
+    pub y: u16,
+    // Same prefix as key names, e.g. "ctrl-".
+    pub modifiers: String
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyEvent {
+    Text(Bytes),
+    Key(String),
+    Mouse(MouseReport)
+}
+
+pub struct KeyDecoder {
+    state: KeyState,
+    sequence: Vec<u8>,
+    text: BytesMut,
+    // The last byte was a CR, so an LF or NUL after it is part of the same Enter.
+    after_cr: bool
+}
+
+impl Default for KeyDecoder {
+    fn default() -> Self {
+        Self {
+            state: KeyState::Ground,
+            sequence: Vec::with_capacity(MAX_SEQUENCE),
+            text: BytesMut::with_capacity(64),
+            after_cr: false
+        }
+    }
+}
+
+// xterm modifier parameters are 1 + a bitmask of shift (1), alt (2) and ctrl (4).
+fn modifier_prefix(bits: u16) -> String {
+    let mut out = String::new();
+    if bits & 4 != 0 {
+        out.push_str("ctrl-");
+    }
+    if bits & 2 != 0 {
+        out.push_str("alt-");
+    }
+    if bits & 1 != 0 {
+        out.push_str("shift-");
+    }
+    out
+}
+
+// How many bytes at the end of data are the start of a UTF-8 character that isn't complete yet.
+fn incomplete_utf8(data: &[u8]) -> usize {
+    for back in 1..=data.len().min(3) {
+        let b = data[data.len() - back];
+        if (b & 0xC0) == 0x80 {
+            continue;
+        }
+        let needed = match b {
+            0xC0..=0xDF => 2,
+            0xE0..=0xEF => 3,
+            0xF0..=0xF7 => 4,
+            _ => 1
+        };
+        return if needed > back { back } else { 0 };
+    }
+    0
+}
+
+fn letter_key(b: u8) -> Option<&'static str> {
+    match b {
+        b'A' => Some("up"),
+        b'B' => Some("down"),
+        b'C' => Some("right"),
+        b'D' => Some("left"),
+        b'H' => Some("home"),
+        b'F' => Some("end"),
+        b'P' => Some("f1"),
+        b'Q' => Some("f2"),
+        b'R' => Some("f3"),
+        b'S' => Some("f4"),
+        _ => None
+    }
+}
+
+// ESC [ n ~
+fn tilde_key(n: u16) -> Option<&'static str> {
+    match n {
+        1 | 7 => Some("home"),
+        2 => Some("insert"),
+        3 => Some("delete"),
+        4 | 8 => Some("end"),
+        5 => Some("pageup"),
+        6 => Some("pagedown"),
+        11 => Some("f1"),
+        12 => Some("f2"),
+        13 => Some("f3"),
+        14 => Some("f4"),
+        15 => Some("f5"),
+        17 => Some("f6"),
+        18 => Some("f7"),
+        19 => Some("f8"),
+        20 => Some("f9"),
+        21 => Some("f10"),
+        23 => Some("f11"),
+        24 => Some("f12"),
+        _ => None
+    }
+}
+
+fn mouse_report(code: u16, x: u16, y: u16, release: bool) -> MouseReport {
+    let button = if code & 64 != 0 {
+        match code & 3 {
+            0 => "wheel-up",
+            1 => "wheel-down",
+            2 => "wheel-left",
+            _ => "wheel-right"
+        }
+    } else {
+        match code & 3 {
+            0 => "left",
+            1 => "middle",
+            2 => "right",
+            _ => "none"
+        }
+    };
+    let action = if release {
+        "release"
+    } else if code & 32 != 0 {
+        "move"
+    } else {
+        "press"
+    };
+    // The mouse encodes shift, alt and ctrl as 4, 8 and 16.
+    MouseReport { button, action, x, y, modifiers: modifier_prefix((code >> 2) & 7) }
+}
+
+impl KeyDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Moves pending text into out, except for a UTF-8 character that isn't complete yet.
+    fn flush_text(&mut self, out: &mut Vec<KeyEvent>, utf8: bool) {
+        let keep = if utf8 { incomplete_utf8(&self.text) } else { 0 };
+        let ready = self.text.len() - keep;
+        if ready > 0 {
+            out.push(KeyEvent::Text(self.text.split_to(ready).freeze()));
+        }
+    }
+
+    fn key(&mut self, out: &mut Vec<KeyEvent>, utf8: bool, name: String) {
+        self.flush_text(out, utf8);
+        out.push(KeyEvent::Key(name));
+    }
+
+    fn params(&self) -> Vec<u16> {
+        let start = usize::from(self.sequence.first() == Some(&b'<'));
+        String::from_utf8_lossy(&self.sequence[start..])
+            .split(';')
+            .map(|p| p.parse().unwrap_or(0))
+            .collect()
+    }
+
+    fn finish_csi(&mut self, out: &mut Vec<KeyEvent>, utf8: bool, mouse: bool, last: u8) {
+        let params = self.params();
+        let modifiers = modifier_prefix(params.get(1).copied().unwrap_or(1).saturating_sub(1));
+
+        if self.sequence.first() == Some(&b'<') {
+            if mouse && (last == b'M' || last == b'm') && params.len() == 3 {
+                self.flush_text(out, utf8);
+                out.push(KeyEvent::Mouse(mouse_report(params[0], params[1], params[2], last == b'm')));
+            }
+            return;
+        }
+
+        let name = match last {
+            b'Z' => Some("tab"),
+            b'~' => tilde_key(params[0]),
+            _ => letter_key(last)
+        };
+        if let Some(name) = name {
+            // Shift-tab has no modifier parameter, the Z says it all.
+            let prefix = if last == b'Z' { "shift-".to_string() } else { modifiers };
+            self.key(out, utf8, format!("{}{}", prefix, name));
+        }
+    }
+
+    // Feeds more client bytes in and returns the keystrokes they complete. utf8 says whether the
+    // client's charset is UTF-8, so characters split across reads stay in one piece. mouse says
+    // whether to decode mouse reports.
+    pub fn feed(&mut self, data: &[u8], utf8: bool, mouse: bool) -> Vec<KeyEvent> {
+        let mut out = Vec::new();
+
+        for &b in data {
+            match self.state {
+                KeyState::Ground => {
+                    let after_cr = std::mem::replace(&mut self.after_cr, b == codes::CR);
+                    match b {
+                        codes::CR => self.key(&mut out, utf8, "enter".to_string()),
+                        codes::LF | codes::NULL if after_cr => {},
+                        codes::LF => self.key(&mut out, utf8, "enter".to_string()),
+                        codes::NULL => {},
+                        BS | DEL => self.key(&mut out, utf8, "backspace".to_string()),
+                        TAB => self.key(&mut out, utf8, "tab".to_string()),
+                        ESC => self.state = KeyState::Esc,
+                        1..=26 => self.key(&mut out, utf8, format!("ctrl-{}", (b'a' + b - 1) as char)),
+                        _ if b < 0x20 => {},
+                        _ => self.text.extend_from_slice(&[b])
+                    }
+                },
+                KeyState::Esc => {
+                    self.sequence.clear();
+                    match b {
+                        b'[' => self.state = KeyState::Csi,
+                        b'O' => self.state = KeyState::Ss3,
+                        // Pressing Escape twice.
+                        ESC => self.key(&mut out, utf8, "escape".to_string()),
+                        // Most terminals send alt-x as ESC x.
+                        0x21..=0x7e => {
+                            self.key(&mut out, utf8, format!("alt-{}", b as char));
+                            self.state = KeyState::Ground;
+                        },
+                        _ => {
+                            self.key(&mut out, utf8, "escape".to_string());
+                            self.state = KeyState::Ground;
+                        }
+                    }
+                },
+                KeyState::Csi => {
+                    match b {
+                        b'M' if mouse && self.sequence.is_empty() => self.state = KeyState::X10Mouse,
+                        0x40..=0x7e => {
+                            self.finish_csi(&mut out, utf8, mouse, b);
+                            self.state = KeyState::Ground;
+                        },
+                        // Parameter and intermediate bytes.
+                        0x20..=0x3f if self.sequence.len() < MAX_SEQUENCE => self.sequence.push(b),
+                        _ => self.state = KeyState::Ground
+                    }
+                },
+                KeyState::Ss3 => {
+                    let name = match b {
+                        b'M' => Some("enter"),
+                        _ => letter_key(b)
+                    };
+                    if let Some(name) = name {
+                        self.key(&mut out, utf8, name.to_string());
+                    }
+                    self.state = KeyState::Ground;
+                },
+                KeyState::X10Mouse => {
+                    self.sequence.push(b);
+                    if self.sequence.len() == 3 {
+                        // Each byte is offset by 32. Button 3 means released, without saying which.
+                        let code = self.sequence[0].saturating_sub(32) as u16;
+                        let x = self.sequence[1].saturating_sub(32) as u16;
+                        let y = self.sequence[2].saturating_sub(32) as u16;
+                        self.flush_text(&mut out, utf8);
+                        out.push(KeyEvent::Mouse(mouse_report(code, x, y, code & 3 == 3 && code & 64 == 0)));
+                        self.state = KeyState::Ground;
+                    }
+                }
+            }
+        }
+
+        // Terminals send a whole sequence at once, so an ESC on its own at the end of a read is
+        // the Escape key.
+        if self.state == KeyState::Esc {
+            self.key(&mut out, utf8, "escape".to_string());
+            self.state = KeyState::Ground;
+        }
+        self.flush_text(&mut out, utf8);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(name: &str) -> KeyEvent {
+        KeyEvent::Key(name.to_string())
+    }
+
+    fn text(s: &str) -> KeyEvent {
+        KeyEvent::Text(Bytes::copy_from_slice(s.as_bytes()))
+    }
+
+    #[test]
+    fn arrows_and_function_keys() {
+        let mut keys = KeyDecoder::new();
+        // CSI form.
+        assert_eq!(keys.feed(b"\x1b[A\x1b[B\x1b[C\x1b[D\x1b[H\x1b[F", true, false),
+            [key("up"), key("down"), key("right"), key("left"), key("home"), key("end")]);
+        assert_eq!(keys.feed(b"\x1b[15~\x1b[24~\x1b[3~\x1b[5~", true, false),
+            [key("f5"), key("f12"), key("delete"), key("pageup")]);
+        // SS3 form, as sent in application cursor mode and for F1-F4.
+        assert_eq!(keys.feed(b"\x1bOA\x1bOD\x1bOP\x1bOS\x1bOM", true, false),
+            [key("up"), key("left"), key("f1"), key("f4"), key("enter")]);
+    }
+
+    #[test]
+    fn modifiers() {
+        let mut keys = KeyDecoder::new();
+        assert_eq!(keys.feed(b"\x1b[1;5D\x1b[1;2A\x1b[15;3~\x1b[Z", true, false),
+            [key("ctrl-left"), key("shift-up"), key("alt-f5"), key("shift-tab")]);
+        assert_eq!(keys.feed(b"\x1bx\x03\t\x7f", true, false),
+            [key("alt-x"), key("ctrl-c"), key("tab"), key("backspace")]);
+    }
+
+    #[test]
+    fn text_and_enter() {
+        let mut keys = KeyDecoder::new();
+        assert_eq!(keys.feed(b"hi\x1b[Ayo\r\n", true, false), [text("hi"), key("up"), text("yo"), key("enter")]);
+        // CR NUL and bare LF are one Enter each.
+        assert_eq!(keys.feed(b"\r\0\n", true, false), [key("enter"), key("enter")]);
+    }
+
+    #[test]
+    fn split_across_reads() {
+        let mut keys = KeyDecoder::new();
+        assert_eq!(keys.feed(b"a\x1b[1;", true, false), [text("a")]);
+        assert_eq!(keys.feed(b"5C", true, false), [key("ctrl-right")]);
+        assert_eq!(keys.feed(b"\x1bO", true, false), []);
+        assert_eq!(keys.feed(b"B", true, false), [key("down")]);
+        // A UTF-8 character cut in two comes out whole.
+        assert_eq!(keys.feed(b"caf\xc3", true, false), [text("caf")]);
+        assert_eq!(keys.feed(b"\xa9", true, false), [text("\u{e9}")]);
+        // ESC alone at the end of a read is the Escape key.
+        assert_eq!(keys.feed(b"\x1b", true, false), [key("escape")]);
+    }
+
+    #[test]
+    fn mouse() {
+        let mut keys = KeyDecoder::new();
+        let report = |button, action, x, y, modifiers: &str| KeyEvent::Mouse(MouseReport { button, action, x, y, modifiers: modifiers.to_string() });
+        assert_eq!(keys.feed(b"\x1b[<0;3;4M\x1b[<0;3;4m\x1b[<65;1;1M", true, true),
+            [report("left", "press", 3, 4, ""), report("left", "release", 3, 4, ""), report("wheel-down", "press", 1, 1, "")]);
+        assert_eq!(keys.feed(b"\x1b[M\x30\x25\x26", true, true), [report("left", "press", 5, 6, "ctrl-")]);
+        // Without mouse tracking the reports are dropped.
+        assert_eq!(keys.feed(b"\x1b[<0;3;4Mok", true, false), [text("ok")]);
+    }
+}
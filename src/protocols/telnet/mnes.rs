@@ -0,0 +1,80 @@
+use bytes::{BufMut, Bytes, BytesMut};
+
+// NEW-ENVIRON (RFC 1572) commands and value markers, as used by MNES.
+pub const IS: u8 = 0;
+pub const SEND: u8 = 1;
+pub const INFO: u8 = 2;
+
+pub const VAR: u8 = 0;
+pub const VAL: u8 = 1;
+pub const ESC: u8 = 2;
+pub const USERVAR: u8 = 3;
+
+// The variables the MNES standard defines. We ask for these by name.
+pub const MNES_VARIABLES: &[&str] = &[
+    "CHARSET",
+    "CLIENT_NAME",
+    "CLIENT_VERSION",
+    "IPADDRESS",
+    "MTTS",
+    "TERMINAL_TYPE"
+];
+
+// Builds the IAC SB NEW-ENVIRON SEND ... payload requesting every MNES variable.
+pub fn request() -> Bytes {
+    let mut out = BytesMut::new();
+    out.put_u8(SEND);
+    for name in MNES_VARIABLES {
+        out.put_u8(VAR);
+        out.put(name.as_bytes());
+    }
+    out.freeze()
+}
+
+// Parses an IS or INFO payload into name/value pairs. Returns None for anything else (such as
+// a client trying to SEND to us). A variable with no VAL comes back with an empty value.
+pub fn decode(data: &[u8]) -> Option<(u8, Vec<(String, String)>)> {
+    let (&command, rest) = data.split_first()?;
+    if command != IS && command != INFO {
+        return None;
+    }
+
+    let mut out: Vec<(String, String)> = Vec::new();
+    let mut current = Vec::new();
+    let mut in_value = false;
+    let mut iter = rest.iter();
+
+    fn finish(out: &mut Vec<(String, String)>, current: &mut Vec<u8>, in_value: bool) {
+        let s = String::from_utf8_lossy(current).to_string();
+        current.clear();
+        if in_value {
+            if let Some(last) = out.last_mut() {
+                last.1 = s;
+            }
+        } else if !s.is_empty() {
+            out.push((s, String::new()));
+        }
+    }
+
+    while let Some(&b) = iter.next() {
+        match b {
+            VAR | USERVAR => {
+                finish(&mut out, &mut current, in_value);
+                in_value = false;
+            },
+            VAL => {
+                finish(&mut out, &mut current, in_value);
+                in_value = true;
+            },
+            ESC => {
+                if let Some(&escaped) = iter.next() {
+                    current.push(escaped);
+                }
+            },
+            _ => current.push(b)
+        }
+    }
+    finish(&mut out, &mut current, in_value);
+
+    Some((command, out))
+}
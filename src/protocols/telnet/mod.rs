@@ -1,3 +1,14 @@
+pub mod charset;
 pub mod codec;
 pub mod codes;
-pub mod protocol;
\ No newline at end of file
+pub mod gmcp;
+pub mod handlers;
+pub mod input;
+pub mod keys;
+pub mod mnes;
+pub mod msdp;
+pub mod mssp;
+pub mod options;
+pub mod protocol;
+pub mod qmethod;
+pub mod quirks;
\ No newline at end of file
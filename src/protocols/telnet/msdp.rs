@@ -0,0 +1,287 @@
+use std::collections::HashMap;
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+use serde_json::{Map, Value as JsonValue};
+
+use crate::protocols::MudData;
+
+use super::codes as tc;
+
+// MSDP is a flat stream of MSDP_VAR <name> MSDP_VAL <value> pairs. A value is either a plain
+// string or a nested TABLE (more VAR/VAL pairs) or ARRAY (a run of VALs). MSDP has no types,
+// so everything scalar arrives as a string.
+//
+// Each top-level variable becomes one MudData whose cmd is the variable name. Tables are
+// merged into kwargs, arrays extend args, and scalars are pushed onto args. Encoding does the
+// reverse so that a MudData survives the round trip.
+
+struct MsdpParser<'a> {
+    data: &'a [u8],
+    pos: usize
+}
+
+impl<'a> MsdpParser<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            pos: 0
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.data.get(self.pos).copied()
+    }
+
+    fn is_token(byte: u8) -> bool {
+        (tc::MSDP_VAR..=tc::MSDP_ARRAY_CLOSE).contains(&byte)
+    }
+
+    fn read_string(&mut self) -> String {
+        let start = self.pos;
+        while let Some(b) = self.peek() {
+            if Self::is_token(b) {
+                break;
+            }
+            self.pos += 1;
+        }
+        String::from_utf8_lossy(&self.data[start..self.pos]).to_string()
+    }
+
+    fn read_value(&mut self) -> JsonValue {
+        match self.peek() {
+            Some(tc::MSDP_TABLE_OPEN) => {
+                self.pos += 1;
+                JsonValue::Object(self.read_table())
+            },
+            Some(tc::MSDP_ARRAY_OPEN) => {
+                self.pos += 1;
+                JsonValue::Array(self.read_array())
+            },
+            _ => JsonValue::String(self.read_string())
+        }
+    }
+
+    // Reads every VAL following a VAR. A VAR with no values at all yields an empty Vec.
+    fn read_values(&mut self) -> Vec<JsonValue> {
+        let mut out = Vec::new();
+        while self.peek() == Some(tc::MSDP_VAL) {
+            self.pos += 1;
+            out.push(self.read_value());
+        }
+        out
+    }
+
+    fn read_table(&mut self) -> Map<String, JsonValue> {
+        let mut out = Map::new();
+        while let Some(b) = self.peek() {
+            match b {
+                tc::MSDP_TABLE_CLOSE => {
+                    self.pos += 1;
+                    break;
+                },
+                tc::MSDP_VAR => {
+                    self.pos += 1;
+                    let name = self.read_string();
+                    let mut values = self.read_values();
+                    let value = match values.len() {
+                        0 => JsonValue::String(String::new()),
+                        1 => values.remove(0),
+                        _ => JsonValue::Array(values)
+                    };
+                    out.insert(name, value);
+                },
+                _ => {
+                    // Stray bytes or mismatched closers. Skip them.
+                    self.pos += 1;
+                }
+            }
+        }
+        out
+    }
+
+    fn read_array(&mut self) -> Vec<JsonValue> {
+        let mut out = Vec::new();
+        while let Some(b) = self.peek() {
+            match b {
+                tc::MSDP_ARRAY_CLOSE => {
+                    self.pos += 1;
+                    break;
+                },
+                tc::MSDP_VAL => {
+                    self.pos += 1;
+                    out.push(self.read_value());
+                },
+                _ => {
+                    self.pos += 1;
+                }
+            }
+        }
+        out
+    }
+
+    fn read_commands(&mut self) -> Vec<MudData> {
+        let mut out = Vec::new();
+        while let Some(b) = self.peek() {
+            if b != tc::MSDP_VAR {
+                self.pos += 1;
+                continue;
+            }
+            self.pos += 1;
+            let mut d = MudData {
+                cmd: self.read_string(),
+                args: Vec::new(),
+                kwargs: HashMap::new()
+            };
+            for value in self.read_values() {
+                match value {
+                    JsonValue::Object(table) => d.kwargs.extend(table),
+                    JsonValue::Array(array) => d.args.extend(array),
+                    other => d.args.push(other)
+                }
+            }
+            out.push(d);
+        }
+        out
+    }
+}
+
+pub fn decode(data: &[u8]) -> Vec<MudData> {
+    MsdpParser::new(data).read_commands()
+}
+
+fn encode_value(out: &mut BytesMut, value: &JsonValue) {
+    match value {
+        JsonValue::Object(table) => {
+            out.put_u8(tc::MSDP_TABLE_OPEN);
+            for (k, v) in table {
+                out.put_u8(tc::MSDP_VAR);
+                out.put(k.as_bytes());
+                out.put_u8(tc::MSDP_VAL);
+                encode_value(out, v);
+            }
+            out.put_u8(tc::MSDP_TABLE_CLOSE);
+        },
+        JsonValue::Array(array) => {
+            out.put_u8(tc::MSDP_ARRAY_OPEN);
+            for v in array {
+                out.put_u8(tc::MSDP_VAL);
+                encode_value(out, v);
+            }
+            out.put_u8(tc::MSDP_ARRAY_CLOSE);
+        },
+        JsonValue::String(s) => out.put(s.as_bytes()),
+        JsonValue::Bool(b) => out.put_u8(if *b { b'1' } else { b'0' }),
+        JsonValue::Number(n) => out.put(n.to_string().as_bytes()),
+        JsonValue::Null => {}
+    }
+}
+
+pub fn encode(d: &MudData) -> Bytes {
+    let mut out = BytesMut::new();
+    out.put_u8(tc::MSDP_VAR);
+    out.put(d.cmd.as_bytes());
+
+    // A single arg is sent as a plain value, several as an array. kwargs always go out as a
+    // table. If both are present, the client gets two VALs: the array and then the table.
+    match d.args.len() {
+        0 => {},
+        1 => {
+            out.put_u8(tc::MSDP_VAL);
+            encode_value(&mut out, &d.args[0]);
+        },
+        _ => {
+            out.put_u8(tc::MSDP_VAL);
+            encode_value(&mut out, &JsonValue::Array(d.args.clone()));
+        }
+    }
+
+    if !d.kwargs.is_empty() {
+        out.put_u8(tc::MSDP_VAL);
+        encode_value(&mut out, &JsonValue::Object(d.kwargs.clone().into_iter().collect()));
+    }
+
+    if d.args.is_empty() && d.kwargs.is_empty() {
+        // MSDP always expects a VAL after a VAR, even if it's empty.
+        out.put_u8(tc::MSDP_VAL);
+    }
+
+    out.freeze()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn data(cmd: &str, args: Vec<JsonValue>, kwargs: JsonValue) -> MudData {
+        MudData {
+            cmd: cmd.to_string(),
+            args,
+            kwargs: kwargs.as_object().cloned().unwrap_or_default().into_iter().collect()
+        }
+    }
+
+    fn round_trip(d: MudData) {
+        let decoded = decode(&encode(&d));
+        assert_eq!(decoded.len(), 1);
+        assert_eq!(decoded[0].cmd, d.cmd);
+        assert_eq!(decoded[0].args, d.args);
+        assert_eq!(decoded[0].kwargs, d.kwargs);
+    }
+
+    #[test]
+    fn scalar() {
+        let d = data("HEALTH", vec![json!("100")], json!({}));
+        assert_eq!(&encode(&d)[..], b"\x01HEALTH\x02100");
+        round_trip(d);
+    }
+
+    #[test]
+    fn nested_table() {
+        round_trip(data("ROOM", vec![], json!({
+            "VNUM": "6008",
+            "EXITS": {"n": "6011", "e": "6007"},
+            "ITEMS": ["sword", "shield"]
+        })));
+    }
+
+    #[test]
+    fn array() {
+        let d = data("REPORTABLE_VARIABLES", vec![json!("HEALTH"), json!("MANA"), json!("ROOM")], json!({}));
+        assert_eq!(&encode(&d)[..], b"\x01REPORTABLE_VARIABLES\x02\x05\x02HEALTH\x02MANA\x02ROOM\x06");
+        round_trip(d);
+    }
+
+    #[test]
+    fn several_variables() {
+        let decoded = decode(b"\x01LIST\x02COMMANDS\x01REPORT\x02HEALTH\x02MANA");
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(decoded[0].cmd, "LIST");
+        assert_eq!(decoded[0].args, vec![json!("COMMANDS")]);
+        assert_eq!(decoded[1].cmd, "REPORT");
+        assert_eq!(decoded[1].args, vec![json!("HEALTH"), json!("MANA")]);
+    }
+
+    #[test]
+    fn malformed() {
+        // A table that is never closed keeps what it has so far.
+        let decoded = decode(b"\x01ROOM\x02\x03\x01VNUM\x026008\x01NAME\x02Town");
+        assert_eq!(decoded.len(), 1);
+        assert_eq!(decoded[0].kwargs.get("VNUM"), Some(&json!("6008")));
+        assert_eq!(decoded[0].kwargs.get("NAME"), Some(&json!("Town")));
+
+        // Unterminated array, stray closers and garbage before the first VAR.
+        let decoded = decode(b"junk\x04\x06\x01GROUP\x02\x05\x02a\x04\x02b");
+        assert_eq!(decoded.len(), 1);
+        assert_eq!(decoded[0].cmd, "GROUP");
+        assert_eq!(decoded[0].args, vec![json!("a"), json!("b")]);
+
+        // A VAR cut off before its VAL, and one with no name at all.
+        let decoded = decode(b"\x01HEALTH");
+        assert_eq!(decoded.len(), 1);
+        assert!(decoded[0].args.is_empty());
+        assert_eq!(decode(b"\x01").len(), 1);
+        assert!(decode(b"").is_empty());
+    }
+}
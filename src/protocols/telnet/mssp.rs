@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+
+use bytes::{BufMut, Bytes, BytesMut};
+
+use serde_json::Value as JsonValue;
+
+use crate::protocols::telnet::codes as tc;
+
+// MSSP (Mud Server Status Protocol) lets listing sites crawl a game's name, player count and
+// so on. The game pushes its variables over the link once, and the portal answers crawlers
+// from this cache by itself, so they get a reply even while the game is down and never show
+// up as player connections.
+//
+// Crawlers ask in one of two ways: telnet negotiation (IAC DO MSSP, answered with
+// IAC SB MSSP MSSP_VAR <name> MSSP_VAL <value>... IAC SE), or by sending the line MSSP-REQUEST,
+// answered in plain text between MSSP-REPLY-START and MSSP-REPLY-END before disconnecting.
+
+pub const REQUEST_LINE: &str = "MSSP-REQUEST";
+
+#[derive(Debug, Clone)]
+pub struct MsspCache {
+    // The variables the game sent, as-is. Arrays become multiple values.
+    pub data: HashMap<String, JsonValue>,
+    // Kept up to date by the portal as clients come and go.
+    pub players: usize,
+    // Unix time the portal started, reported as UPTIME.
+    pub started: i64
+}
+
+impl Default for MsspCache {
+    fn default() -> Self {
+        Self {
+            data: Default::default(),
+            players: 0,
+            started: chrono::Utc::now().timestamp()
+        }
+    }
+}
+
+pub fn value_strings(v: &JsonValue) -> Vec<String> {
+    match v {
+        JsonValue::Array(a) => a.iter().flat_map(value_strings).collect(),
+        JsonValue::String(s) => vec![s.clone()],
+        JsonValue::Bool(b) => vec![if *b { "1" } else { "0" }.to_string()],
+        JsonValue::Null => Vec::new(),
+        other => vec![other.to_string()]
+    }
+}
+
+impl MsspCache {
+    // Everything a crawler gets: the configured defaults, overridden by what the game sent,
+    // plus the live PLAYERS and UPTIME the portal knows better than anyone.
+    pub fn variables(&self, defaults: &HashMap<String, JsonValue>) -> Vec<(String, Vec<String>)> {
+        let mut merged: HashMap<String, Vec<String>> = HashMap::new();
+        for (k, v) in defaults.iter().chain(self.data.iter()) {
+            merged.insert(k.to_uppercase(), value_strings(v));
+        }
+        merged.insert("PLAYERS".to_string(), vec![self.players.to_string()]);
+        merged.insert("UPTIME".to_string(), vec![self.started.to_string()]);
+
+        let mut out: Vec<(String, Vec<String>)> = merged.into_iter().collect();
+        // NAME first, as some crawlers expect, then alphabetical for a stable reply.
+        out.sort_by(|a, b| (a.0 != "NAME", &a.0).cmp(&(b.0 != "NAME", &b.0)));
+        out
+    }
+}
+
+// The payload of IAC SB MSSP ... IAC SE.
+pub fn encode(vars: &[(String, Vec<String>)]) -> Bytes {
+    let mut out = BytesMut::new();
+    for (name, values) in vars {
+        out.put_u8(tc::MSSP_VAR);
+        out.put(name.as_bytes());
+        if values.is_empty() {
+            out.put_u8(tc::MSSP_VAL);
+        }
+        for v in values {
+            out.put_u8(tc::MSSP_VAL);
+            out.put(v.as_bytes());
+        }
+    }
+    out.freeze()
+}
+
+// The plain-text reply to MSSP-REQUEST. Values of one variable are tab separated.
+pub fn encode_text(vars: &[(String, Vec<String>)]) -> String {
+    let mut out = String::from("\r\nMSSP-REPLY-START\r\n");
+    for (name, values) in vars {
+        out.push_str(name);
+        for v in values {
+            out.push('\t');
+            out.push_str(v);
+        }
+        out.push_str("\r\n");
+    }
+    out.push_str("MSSP-REPLY-END\r\n");
+    out
+}
@@ -0,0 +1,183 @@
+use std::{
+    collections::BTreeMap,
+    sync::Arc
+};
+
+use bytes::Bytes;
+
+use crate::{
+    config::Config,
+    protocols::{
+        telnet::{
+            charset::Charset,
+            codec::TelnetEvent,
+            codes as tc,
+            handlers
+        },
+        {MudData, ProtocolCapabilities}
+    }
+};
+
+// Every telnet option the portal speaks is a TelnetOptionHandler. Each connection gets its own
+// set of handlers, built from the registry of the listener that accepted it. TelnetProtocol runs
+// the negotiation itself (see qmethod) and calls into the handler when the option is switched
+// on or off, or when the client sends a subnegotiation for it.
+//
+// Hooks are synchronous. Anything a handler wants done to the connection goes through the
+// OptionContext, and the protocol carries it out once the hook returns.
+
+// Which sides of an option we're willing to have on, and which we ask for when a client
+// connects. Local is what we do (WILL/WONT), remote is what the client does (DO/DONT).
+#[derive(Default, Debug, Clone, Copy)]
+pub struct OptionPolicy {
+    pub allow_local: bool,
+    pub allow_remote: bool,
+    pub start_local: bool,
+    pub start_remote: bool,
+    // The game may switch our side on mid-session, even though we refuse it when the client
+    // asks. ECHO works this way.
+    pub game_local: bool,
+}
+
+impl OptionPolicy {
+    // Offered by us from the start, never accepted from the client.
+    pub fn local() -> Self {
+        Self { allow_local: true, start_local: true, ..Default::default() }
+    }
+
+    // Requested from the client from the start, never done by us.
+    pub fn remote() -> Self {
+        Self { allow_remote: true, start_remote: true, ..Default::default() }
+    }
+}
+
+pub enum OptionEffect {
+    Send(TelnetEvent),
+    // Passed on to the game as if the client had sent it.
+    ToGame(MudData),
+    SetCharset(Charset),
+    StartTls,
+    // Whether the codec should start inflating when the client begins MCCP3.
+    AcceptMccp3(bool),
+    // Something in the capabilities changed and the game should hear about it.
+    CapabilitiesChanged
+}
+
+pub struct OptionContext<'a> {
+    pub capabilities: &'a mut ProtocolCapabilities,
+    pub config: &'a Config,
+    effects: Vec<OptionEffect>
+}
+
+impl<'a> OptionContext<'a> {
+    pub fn new(capabilities: &'a mut ProtocolCapabilities, config: &'a Config) -> Self {
+        Self { capabilities, config, effects: Vec::new() }
+    }
+
+    pub fn send(&mut self, te: TelnetEvent) {
+        self.effects.push(OptionEffect::Send(te));
+    }
+
+    pub fn to_game(&mut self, d: MudData) {
+        self.effects.push(OptionEffect::ToGame(d));
+    }
+
+    pub fn set_charset(&mut self, c: Charset) {
+        self.effects.push(OptionEffect::SetCharset(c));
+    }
+
+    pub fn start_tls(&mut self) {
+        self.effects.push(OptionEffect::StartTls);
+    }
+
+    pub fn accept_mccp3(&mut self, accept: bool) {
+        self.effects.push(OptionEffect::AcceptMccp3(accept));
+    }
+
+    pub fn capabilities_changed(&mut self) {
+        self.effects.push(OptionEffect::CapabilitiesChanged);
+    }
+
+    pub fn into_effects(self) -> Vec<OptionEffect> {
+        self.effects
+    }
+}
+
+pub trait TelnetOptionHandler: Send + Sync {
+    fn policy(&self) -> OptionPolicy;
+
+    fn enable_local(&mut self, _ctx: &mut OptionContext) {}
+    fn disable_local(&mut self, _ctx: &mut OptionContext) {}
+    fn enable_remote(&mut self, _ctx: &mut OptionContext) {}
+    fn disable_remote(&mut self, _ctx: &mut OptionContext) {}
+
+    // IAC SB <option> <data> IAC SE from the client, already un-escaped.
+    fn subnegotiate(&mut self, _ctx: &mut OptionContext, _data: Bytes) {}
+
+    // Writes this option's part of the capabilities, given which sides are on. Called every time
+    // the capabilities are refreshed, before the player's // overrides are applied.
+    fn capabilities(&self, _local: bool, _remote: bool, _caps: &mut ProtocolCapabilities) {}
+
+    // True while the option still has handshaking of its own to finish, such as cycling through
+    // terminal types. The game isn't told about the client until every handler is done.
+    fn negotiating(&self) -> bool {
+        false
+    }
+}
+
+// The names telnet.disabled_options and telnet.tls_disabled_options use for each option.
+pub fn option_code(name: &str) -> Option<u8> {
+    match name.to_lowercase().as_str() {
+        "sga" => Some(tc::SGA),
+        "echo" => Some(tc::ECHO),
+        "eor" => Some(tc::TELOPT_EOR),
+        "linemode" => Some(tc::LINEMODE),
+        "mccp2" => Some(tc::MCCP2),
+        "mccp3" => Some(tc::MCCP3),
+        "mxp" => Some(tc::MXP),
+        "mssp" => Some(tc::MSSP),
+        "gmcp" => Some(tc::GMCP),
+        "msdp" => Some(tc::MSDP),
+        "charset" => Some(tc::CHARSET),
+        "naws" => Some(tc::NAWS),
+        "ttype" | "mtts" => Some(tc::MTTS),
+        "mnes" => Some(tc::MNES),
+        "starttls" => Some(tc::START_TLS),
+        _ => None
+    }
+}
+
+pub type HandlerFactory = Arc<dyn Fn() -> Box<dyn TelnetOptionHandler> + Send + Sync>;
+
+// The options a telnet listener supports, by option code. Start from with_defaults() and
+// register or remove options to change what a listener speaks.
+#[derive(Clone, Default)]
+pub struct TelnetOptionRegistry {
+    factories: BTreeMap<u8, HandlerFactory>
+}
+
+impl TelnetOptionRegistry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_defaults() -> Self {
+        let mut registry = Self::new();
+        handlers::register_defaults(&mut registry);
+        registry
+    }
+
+    // Adds an option, or replaces the handler for one that's already there.
+    pub fn register<F>(&mut self, op: u8, factory: F) where F: Fn() -> Box<dyn TelnetOptionHandler> + Send + Sync + 'static {
+        self.factories.insert(op, Arc::new(factory));
+    }
+
+    pub fn remove(&mut self, op: u8) {
+        self.factories.remove(&op);
+    }
+
+    // Fresh handlers for one connection.
+    pub fn build(&self) -> BTreeMap<u8, Box<dyn TelnetOptionHandler>> {
+        self.factories.iter().map(|(op, f)| (*op, f())).collect()
+    }
+}
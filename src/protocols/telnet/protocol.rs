@@ -1,7 +1,8 @@
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     vec::Vec,
     net::SocketAddr,
+    sync::Arc,
     time::{Duration, Instant},
 };
 
@@ -15,7 +16,7 @@ use tokio_util::codec::{Framed};
 
 use tokio_stream::wrappers::IntervalStream;
 
-use bytes::{BytesMut, Bytes, BufMut, Buf};
+use bytes::{BytesMut, Bytes, BufMut};
 
 use futures::{
     sink::{SinkExt},
@@ -24,70 +25,50 @@ use futures::{
 
 use serde_json::Value as JsonValue;
 
-use once_cell::sync::Lazy;
+use tokio_rustls::TlsAcceptor;
 
 use crate::{
     protocols::{
         telnet::{
+            charset::Charset,
             codec::{TelnetCodec, TelnetEvent},
-            codes as tc
+            codes as tc,
+            gmcp,
+            input::{InputLine, LineNormalizer},
+            keys::{KeyDecoder, KeyEvent},
+            msdp,
+            handlers,
+            options::{OptionContext, OptionEffect, OptionPolicy, TelnetOptionHandler, TelnetOptionRegistry},
+            qmethod::{QOption, QOutcome},
+            mssp
         },
-        {ProtocolCapabilities, Color, ProtocolLink, MudData}
+        ansi,
+        markup,
+        mxp::{self, MxpMode, MxpTranslator},
+        screenreader,
+        wrap,
+        oob::{self, OobRoute},
+        {ProtocolCapabilities, Color, ConnectionStats, ProtocolLink, MudData}
     },
+    config::{Config, KeepaliveMode},
+    networking::tls::StartTls,
     msg::{Msg2MudProtocol, Msg2Portal, Msg2PortalFromClient},
-    util::ensure_crlf
+    util::ensure_crlf,
+    CONFIG,
+    MSSP
 };
 
 
 
-#[derive(Default, Clone)]
-pub struct TelnetOptionPerspective {
-    pub enabled: bool,
-    // Negotiating is true if WE have sent a request.
-    pub negotiating: bool
-}
-
-#[derive(Default, Clone)]
-pub struct TelnetOptionState {
-    pub remote: TelnetOptionPerspective,
-    pub local: TelnetOptionPerspective,
-}
-
-#[derive(Default, Clone)]
-pub struct TelnetOption {
-    pub allow_local: bool,
-    pub allow_remote: bool,
-    pub start_local: bool,
-    pub start_remote: bool,
-}
-
-static TELNET_OPTIONS: Lazy<HashMap<u8, TelnetOption>> = Lazy::new( || {
-    let mut map: HashMap<u8, TelnetOption> = Default::default();
-
-    map.insert(tc::SGA, TelnetOption {allow_local: true, allow_remote: false, start_remote: false, start_local: true});
-    map.insert(tc::NAWS, TelnetOption {allow_local: false, allow_remote: true, start_remote: true, start_local: false});
-    map.insert(tc::MTTS, TelnetOption {allow_local: false, allow_remote: true, start_remote: true, start_local: false});
-    //map.insert(tc::MXP, TelnetOption {allow_local: true, allow_remote: true, start_remote: false, start_local: true});
-    map.insert(tc::MSSP, TelnetOption {allow_local: true, allow_remote: false, start_remote: false, start_local: true});
-    map.insert(tc::MCCP2, TelnetOption {allow_local: true, allow_remote: false, start_remote: false, start_local: true});
-    map.insert(tc::MCCP3, TelnetOption {allow_local: true, allow_remote: false, start_remote: false, start_local: true});
-    map.insert(tc::GMCP, TelnetOption {allow_local: true, allow_remote: false, start_remote: false, start_local: true});
-    map.insert(tc::MSDP, TelnetOption {allow_local: true, allow_remote: false, start_remote: false, start_local: true});
-    map.insert(tc::LINEMODE, TelnetOption {allow_local: false, allow_remote: true, start_remote: true, start_local: false});
-    map.insert(tc::TELOPT_EOR, TelnetOption {allow_local: true, allow_remote: false, start_remote: false, start_local: true});
-    map
-});
-
 #[derive(Default, Debug, Clone)]
 pub struct TelnetHandshakes {
     pub local: HashSet<u8>,
-    pub remote: HashSet<u8>,
-    pub ttype: HashSet<u8>
+    pub remote: HashSet<u8>
 }
 
 impl TelnetHandshakes {
     pub fn len(&self) -> usize {
-        self.local.len() + self.remote.len() + self.ttype.len()
+        self.local.len() + self.remote.len()
     }
 
     pub fn is_empty(&self) -> bool {
@@ -95,31 +76,90 @@ impl TelnetHandshakes {
     }
 }
 
+// How a prompt is marked as finished. Auto uses IAC EOR if the client agreed to it, IAC GA if
+// SGA hasn't suppressed go-aheads, or nothing at all.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PromptTerminator {
+    Auto,
+    Eor,
+    Ga,
+    Nothing
+}
+
+impl PromptTerminator {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name.to_lowercase().as_str() {
+            "auto" => Some(PromptTerminator::Auto),
+            "eor" => Some(PromptTerminator::Eor),
+            "ga" => Some(PromptTerminator::Ga),
+            "none" => Some(PromptTerminator::Nothing),
+            _ => None
+        }
+    }
+}
+
+// Values the player forced with // commands. They win over whatever the client negotiates, and
+// are re-applied every time negotiation changes the capabilities.
+#[derive(Debug, Default, Clone)]
+pub struct CapabilityOverrides {
+    pub color: Option<Color>,
+    pub width: Option<u16>,
+    pub height: Option<u16>,
+    pub charset: Option<Charset>,
+    pub screen_reader: Option<bool>
+}
+
 #[derive(Debug)]
 pub struct TelnetTimers {
     pub last_interval: Instant,
     pub last_keepalive: Instant,
+    // Set once the idle warning has gone out, and cleared by any activity.
+    pub idle_warned: bool,
 }
 
 impl Default for TelnetTimers {
     fn default() -> Self {
         Self {
             last_interval: Instant::now(),
-            last_keepalive: Instant::now()
+            last_keepalive: Instant::now(),
+            idle_warned: false
         }
     }
 }
 
+// Who is on the other end of a new connection, and what the listener offers them.
+pub struct TelnetClientInfo {
+    pub addr: SocketAddr,
+    pub hostnames: Vec<String>,
+    // Already encrypted, by implicit TLS or a sniffed ClientHello.
+    pub tls: bool,
+    // Set if the client may still upgrade with STARTTLS.
+    pub starttls: Option<TlsAcceptor>,
+    pub options: Arc<TelnetOptionRegistry>
+}
+
+// One keystroke for the game in character mode.
+fn key_data(name: String, kwargs: HashMap<String, JsonValue>) -> MudData {
+    MudData {
+        cmd: String::from("key"),
+        args: vec![JsonValue::String(name)],
+        kwargs
+    }
+}
+
 
 pub struct TelnetProtocol<T> {
     // This serves as a higher-level actor that abstracts a bunch of the lower-level
     // nitty-gritty so the Session doesn't need to deal with it.
     conn_id: usize,
-    op_state: HashMap<u8, TelnetOptionState>,
+    op_state: HashMap<u8, QOption>,
+    // What the client and negotiation worked out. config and charset are this with the player's
+    // overrides on top, rebuilt by apply_overrides.
+    negotiated: ProtocolCapabilities,
+    negotiated_charset: Charset,
     config: ProtocolCapabilities,
     handshakes_left: TelnetHandshakes,
-    ttype_count: u8,
-    ttype_last: Option<String>,
+    handlers: BTreeMap<u8, Box<dyn TelnetOptionHandler>>,
     conn: Framed<T, TelnetCodec>,
     active: bool,
     sent_link: bool,
@@ -128,41 +168,80 @@ pub struct TelnetProtocol<T> {
     rx_protocol: Receiver<Msg2MudProtocol>,
     running: bool,
     app_buffer: BytesMut,
+    input: LineNormalizer,
+    // Character mode: every keystroke goes to the game as it's typed, instead of whole lines.
+    char_mode: bool,
+    keys: KeyDecoder,
+    // The game asked for the user's typing to be hidden.
+    secret_input: bool,
     time_created: Instant,
     time_activity: Instant,
     timers: TelnetTimers,
+    portal_config: Config,
+    mxp: MxpTranslator,
+    prompt_terminator: PromptTerminator,
+    charset: Charset,
+    starttls: Option<TlsAcceptor>,
+    overrides: CapabilityOverrides,
+    wrap: bool,
+    // Set for MSSP crawlers, which are answered by the portal and never reach the game.
+    crawler: bool,
 }
 
 
-impl<T> TelnetProtocol<T> where T: AsyncRead + AsyncWrite + Send + 'static + Unpin + Sync {
-    pub fn new(conn_id: usize, conn: Framed<T, TelnetCodec>, addr: SocketAddr, hostnames: Vec<String>, tls: bool, tx_portal: Sender<Msg2Portal>) -> Self {
+impl<T> TelnetProtocol<T> where T: AsyncRead + AsyncWrite + StartTls + Send + 'static + Unpin + Sync {
+    pub fn new(conn_id: usize, conn: Framed<T, TelnetCodec>, client: TelnetClientInfo, tx_portal: Sender<Msg2Portal>) -> Self {
 
         let (tx_protocol, rx_protocol) = channel(10);
+        let portal_config = CONFIG.lock().unwrap().clone();
+        let input = LineNormalizer::new(portal_config.telnet.line_endings.clone(), portal_config.telnet.max_line_length);
+        let TelnetClientInfo { addr, hostnames, tls, starttls, options } = client;
+        let mut handlers = options.build();
+        // STARTTLS is only on offer if there's something to upgrade with.
+        if starttls.is_none() {
+            handlers.remove(&tc::START_TLS);
+        }
         // It reaches here! a println!() works.
         let mut out = Self {
             conn_id,
             op_state: Default::default(),
             conn,
+            negotiated: Default::default(),
+            negotiated_charset: Charset::Utf8,
             config: Default::default(),
             handshakes_left: Default::default(),
             tx_portal,
             tx_protocol,
             rx_protocol,
-            ttype_count: 0,
-            ttype_last: None,
+            handlers,
             active: false,
             sent_link: false,
             running: true,
             app_buffer: BytesMut::with_capacity(1024),
+            input,
+            char_mode: false,
+            keys: KeyDecoder::new(),
+            secret_input: false,
             time_created: Instant::now(),
             time_activity: Instant::now(),
             timers: Default::default(),
+            portal_config,
+            mxp: Default::default(),
+            prompt_terminator: PromptTerminator::Auto,
+            charset: Charset::Utf8,
+            starttls,
+            overrides: Default::default(),
+            wrap: false,
+            crawler: false,
         };
         // Stack overflow before reaching this point.
-        out.config.tls = tls;
-        out.config.host_address = addr.ip().to_string();
-        out.config.host_port = addr.port();
-        out.config.host_names = hostnames;
+        out.negotiated.tls = tls;
+        out.negotiated.encryption = tls;
+        out.negotiated.host_address = addr.ip().to_string();
+        out.negotiated.host_port = addr.port();
+        out.negotiated.host_names = hostnames;
+        out.apply_overrides();
+        out.wrap = out.portal_config.telnet.wrap;
         out
     }
 
@@ -174,30 +253,48 @@ impl<T> TelnetProtocol<T> where T: AsyncRead + AsyncWrite + Send + 'static + Unp
         }
     }
 
+    fn make_stats(&self) -> ConnectionStats {
+        let codec = self.conn.codec().stats();
+        ConnectionStats {
+            connected_secs: self.time_created.elapsed().as_secs(),
+            idle_secs: self.time_activity.elapsed().as_secs(),
+            bytes_in: codec.raw_in,
+            bytes_out: codec.raw_out,
+            wire_bytes_in: codec.wire_in,
+            wire_bytes_out: codec.wire_out
+        }
+    }
+
     async fn handle_conn(&mut self, t_msg: Option<Result<TelnetEvent, std::io::Error>>) {
         if let Some(msg) = t_msg {
             self.time_activity = Instant::now();
+            self.timers.idle_warned = false;
             match msg {
                 Ok(msg) => {
                     let _ = self.process_telnet_event(msg).await;
                 },
                 Err(e) => {
-                    let _ = self.tx_portal.send(Msg2Portal::ClientDisconnected(self.conn_id, String::from("dunno yet"))).await;
-                    self.running = false;
+                    self.disconnect(e.to_string()).await;
                 }
             }
         } else {
-            let _ = self.tx_portal.send(Msg2Portal::ClientDisconnected(self.conn_id, String::from("dunno yet"))).await;
-            self.running = false;
+            self.disconnect(String::from("connection closed by client")).await;
         }
     }
 
+    // Ends the session from our side and tells the game why.
+    async fn disconnect(&mut self, reason: String) {
+        let _ = self.tx_portal.send(Msg2Portal::ClientDisconnected(self.conn_id, reason)).await;
+        self.running = false;
+    }
+
     async fn send(&mut self, te: TelnetEvent) -> bool {
         match self.conn.send(te).await {
             Ok(_) => true,
             Err(e) => {
-                self.running = false;
-                let _ = self.tx_portal.send(Msg2Portal::ClientDisconnected(self.conn_id, String::from(e.to_string()))).await;
+                if self.running {
+                    self.disconnect(e.to_string()).await;
+                }
                 false
             }
         }
@@ -205,22 +302,21 @@ impl<T> TelnetProtocol<T> where T: AsyncRead + AsyncWrite + Send + 'static + Unp
 
     pub async fn run(&mut self) {
 
-        // Initialize Telnet Op handlers.
-        for (code, tel_op) in TELNET_OPTIONS.iter() {
-
-            let mut state = TelnetOptionState::default();
-            if(tel_op.start_local) {
-                state.local.negotiating = true;
-                self.send(TelnetEvent::Negotiate(tc::WILL, *code)).await;
-                self.handshakes_left.local.insert(*code);
+        // Open negotiation for every option that wants it.
+        let policies: Vec<(u8, OptionPolicy)> = self.handlers.iter().map(|(op, h)| (*op, h.policy())).collect();
+        for (code, policy) in policies {
+            let mut state = QOption::default();
+            if policy.start_local {
+                state.local.request(true);
+                self.send(TelnetEvent::Negotiate(tc::WILL, code)).await;
+                self.handshakes_left.local.insert(code);
             }
-            if(tel_op.start_remote) {
-                state.remote.negotiating = true;
-                self.send(TelnetEvent::Negotiate(tc::DO, *code)).await;
-                self.handshakes_left.remote.insert(*code);
+            if policy.start_remote {
+                state.remote.request(true);
+                self.send(TelnetEvent::Negotiate(tc::DO, code)).await;
+                self.handshakes_left.remote.insert(code);
             }
-            self.op_state.insert(*code, state);
-
+            self.op_state.insert(code, state);
         }
 
         let mut interval_timer = IntervalStream::new(time::interval(Duration::from_millis(100)));
@@ -248,24 +344,54 @@ impl<T> TelnetProtocol<T> where T: AsyncRead + AsyncWrite + Send + 'static + Unp
         }
 
             // Check if negotiations are complete or timed out
-            if in_negotiation_phase && self.handshakes_left.is_empty() {
+            if in_negotiation_phase && self.handshakes_left.is_empty() && !self.handlers.values().any(|h| h.negotiating()) {
                 in_negotiation_phase = false;
             }
 
             // If negotiations have just completed or timed out, send the ClientConnected message
-            if !in_negotiation_phase && !self.sent_link {
+            if !in_negotiation_phase && !self.sent_link && !self.crawler && !self.looks_like_crawler() {
                 let _ = self.tx_portal.send(Msg2Portal::ClientConnected(self.make_link())).await;
                 self.sent_link = true;
                 self.active = true;
                 self.process_app_buffer().await;
             }
         }
+
+        // Finish the MCCP2 stream properly so the client doesn't see a truncated zlib stream.
+        if self.conn.codec().compressing() {
+            let _ = self.conn.send(TelnetEvent::Negotiate(tc::WONT, tc::MCCP2)).await;
+        }
     }
 
     async fn handle_interval_timer(&mut self, ins: Instant) {
+        let telnet = &self.portal_config.telnet;
+        let idle_timeout = Duration::from_secs(telnet.idle_timeout_secs);
+        let idle_warning = Duration::from_secs(telnet.idle_warning_secs);
+        let keepalive = (telnet.keepalive == KeepaliveMode::Nop && telnet.keepalive_secs > 0)
+            .then(|| Duration::from_secs(telnet.keepalive_secs));
+
         // Check if the connection has been utterly idle at a network level for too long.
-        if(self.time_activity.elapsed().as_secs() > (60 * 30)) {
-            // handle disconnect here.
+        if !idle_timeout.is_zero() {
+            let idle = self.time_activity.elapsed();
+            if idle >= idle_timeout {
+                self.disconnect(format!("idle timeout after {} seconds", idle.as_secs())).await;
+                return;
+            }
+            if !idle_warning.is_zero() && !self.timers.idle_warned && idle >= idle_timeout.saturating_sub(idle_warning) {
+                self.timers.idle_warned = true;
+                let msg = self.portal_config.telnet.idle_warning_message.clone();
+                let data = self.charset.encode(&msg);
+                self.send(TelnetEvent::Data(data)).await;
+            }
+        }
+
+        // An IAC NOP does nothing on the client, but writing it is how we find out the socket
+        // has gone away on connections that would otherwise sit silently forever.
+        if let Some(every) = keepalive {
+            if self.timers.last_keepalive.elapsed() >= every {
+                self.timers.last_keepalive = ins;
+                self.send(TelnetEvent::Command(tc::NOP)).await;
+            }
         }
 
         self.timers.last_interval = ins;
@@ -282,6 +408,8 @@ impl<T> TelnetProtocol<T> where T: AsyncRead + AsyncWrite + Send + 'static + Unp
                 self.app_buffer.put(data);
                 if self.sent_link {
                     let _ = self.process_app_buffer().await;
+                } else {
+                    self.check_mssp_request().await;
                 }
             }
         }
@@ -295,33 +423,61 @@ impl<T> TelnetProtocol<T> where T: AsyncRead + AsyncWrite + Send + 'static + Unp
     }
 
     async fn process_app_buffer(&mut self) {
-        loop {
-            // Find the position of the LF character
-            if let Some(ipos) = self.app_buffer.as_ref().iter().position(|b| b == &b'\n') {
-
-                // Extract the line without CR and LF characters
-                let cmd = self.app_buffer.split_to(ipos);
-
-
-                // Convert the line to a String and handle the command
-                if let Ok(s) = String::from_utf8(cmd.to_vec()) {
-                    // strip all \r from the string
-                    let s = s.replace("\r", "");
+        if self.char_mode {
+            return self.process_keys().await;
+        }
+        let data = self.app_buffer.split();
+        let lines = self.input.feed(&data, self.charset == Charset::Utf8);
+
+        for line in lines {
+            match line {
+                InputLine::Line(cmd) => {
+                    // Convert the line to a String using the client's charset and handle the command.
+                    // Bytes that don't fit the charset become U+FFFD rather than losing the line.
+                    let s = self.charset.decode(&cmd);
                     let _ = self.handle_user_command(s).await;
+                },
+                InputLine::TooLong => {
+                    let max = self.portal_config.telnet.max_line_length;
+                    self.send_line(&format!("Your input was longer than {} bytes and has been ignored.", max)).await;
                 }
+            }
+        }
+    }
 
-                // Advance the buffer to consume LF character
-                self.app_buffer.advance(1);
-            } else {
-                // No more complete lines in the buffer, break the loop
-                break;
+    // In character mode there are no lines, so // commands don't exist either. The game decides
+    // what, if anything, to echo.
+    async fn process_keys(&mut self) {
+        let data = self.app_buffer.split();
+        let events = self.keys.feed(&data, self.charset == Charset::Utf8, self.config.mouse_tracking);
+
+        let mut out = Vec::new();
+        for event in events {
+            match event {
+                KeyEvent::Text(text) => {
+                    for c in self.charset.decode(&text).chars() {
+                        out.push(key_data(c.to_string(), Default::default()));
+                    }
+                },
+                KeyEvent::Key(name) => out.push(key_data(name, Default::default())),
+                KeyEvent::Mouse(m) => {
+                    let mut kwargs = HashMap::new();
+                    kwargs.insert("button".to_string(), JsonValue::from(m.button));
+                    kwargs.insert("action".to_string(), JsonValue::from(m.action));
+                    kwargs.insert("x".to_string(), JsonValue::from(m.x));
+                    kwargs.insert("y".to_string(), JsonValue::from(m.y));
+                    out.push(key_data(format!("{}mouse", m.modifiers), kwargs));
+                }
             }
         }
+        if !out.is_empty() {
+            let _ = self.tx_portal.send(Msg2Portal::FromClient(self.conn_id, Msg2PortalFromClient::Data(out))).await;
+        }
     }
 
     async fn handle_user_command(&mut self, cmd: String) {
         if cmd.starts_with("//") {
-            let _ = self.handle_protocol_command(cmd);
+            let _ = self.handle_protocol_command(cmd).await;
         } else if self.sent_link {
             // We must format the command as a Msg2PortalFromClient::Data, so we must encapsulate this in a MudData.
             let d = MudData {
@@ -333,8 +489,149 @@ impl<T> TelnetProtocol<T> where T: AsyncRead + AsyncWrite + Send + 'static + Unp
         }
     }
 
+    // Sends portal-generated text straight to the client, bypassing the game.
+    async fn send_line(&mut self, s: &str) {
+        let data = self.charset.encode(&ensure_crlf(&format!("{}\n", s)));
+        self.send(TelnetEvent::Data(data)).await;
+    }
+
+    // Lines starting with // are for the portal itself and never reach the game. They let
+    // players inspect and correct what negotiation worked out about their client.
     async fn handle_protocol_command(&mut self, cmd: String) {
-        // TODO: Handle protocol commands
+        let mut parts = cmd[2..].split_whitespace();
+        let name = parts.next().unwrap_or("").to_lowercase();
+        let arg = parts.next().map(|a| a.to_lowercase());
+
+        match (name.as_str(), arg.as_deref()) {
+            ("caps" | "capabilities", _) => self.show_capabilities().await,
+            ("stats", _) => self.show_stats().await,
+            ("color" | "colour", Some("auto")) => {
+                self.overrides.color = None;
+                self.send_line("Color depth will follow what your client reports.").await;
+                self.update_capabilities().await;
+            },
+            ("color" | "colour", Some(a)) => match Color::from_name(a) {
+                Some(c) => {
+                    self.send_line(&format!("Color depth set to {}.", c.name())).await;
+                    self.overrides.color = Some(c);
+                    self.update_capabilities().await;
+                },
+                None => self.send_line("Usage: //color <none|16|256|truecolor|auto>").await
+            },
+            ("width", Some(a)) | ("height", Some(a)) => {
+                let value = match a {
+                    "auto" => None,
+                    _ => match a.parse::<u16>() {
+                        Ok(v) if v > 0 => Some(v),
+                        _ => {
+                            self.send_line(&format!("Usage: //{} <number|auto>", name)).await;
+                            return;
+                        }
+                    }
+                };
+                if name == "width" {
+                    self.overrides.width = value;
+                } else {
+                    self.overrides.height = value;
+                }
+                match value {
+                    Some(v) => self.send_line(&format!("Screen {} set to {}.", name, v)).await,
+                    None => self.send_line(&format!("Screen {} will follow what your client reports.", name)).await
+                }
+                self.update_capabilities().await;
+            },
+            ("encoding", Some("auto")) => {
+                self.overrides.charset = None;
+                self.update_capabilities().await;
+                self.send_line("Encoding will follow what your client negotiates.").await;
+            },
+            ("encoding", Some(a)) => match Charset::from_name(a) {
+                Some(c) => {
+                    self.overrides.charset = Some(c);
+                    self.update_capabilities().await;
+                    self.send_line(&format!("Encoding set to {}.", c.name())).await;
+                },
+                None => {
+                    let names: Vec<&str> = self.offered_charsets().iter().map(|c| c.name()).collect();
+                    self.send_line(&format!("Usage: //encoding <{}|auto>", names.join("|"))).await;
+                }
+            },
+            ("wrap", a) => {
+                self.wrap = match a {
+                    Some("on") => true,
+                    Some("off") => false,
+                    _ => !self.wrap
+                };
+                let msg = match self.wrap {
+                    true => format!("Word wrap on, at {} columns.", self.wrap_width()),
+                    false => "Word wrap off.".to_string()
+                };
+                self.send_line(&msg).await;
+            },
+            ("screenreader", Some("auto")) => {
+                self.overrides.screen_reader = None;
+                self.update_capabilities().await;
+                self.send_line(&format!("Screen reader mode will follow what your client reports ({}).", if self.config.screen_reader { "on" } else { "off" })).await;
+            },
+            ("screenreader", a) => {
+                let enable = match a {
+                    Some("on") => true,
+                    Some("off") => false,
+                    _ => !self.config.screen_reader
+                };
+                self.overrides.screen_reader = Some(enable);
+                self.send_line(&format!("Screen reader mode {}.", if enable { "on" } else { "off" })).await;
+                self.update_capabilities().await;
+            },
+            _ => {
+                self.send_line("Portal commands:").await;
+                self.send_line("  //caps                  Show what the portal knows about your client.").await;
+                self.send_line("  //stats                 Show connection statistics.").await;
+                self.send_line("  //color <depth|auto>    Force color depth: none, 16, 256 or truecolor.").await;
+                self.send_line("  //width <n|auto>        Force screen width.").await;
+                self.send_line("  //height <n|auto>       Force screen height.").await;
+                self.send_line("  //encoding <name|auto>  Force text encoding.").await;
+                self.send_line("  //wrap [on|off]         Toggle word wrapping to your screen width.").await;
+                self.send_line("  //screenreader [on|off] Toggle screen reader mode, or auto to follow your client.").await;
+            }
+        }
+    }
+
+    async fn show_capabilities(&mut self) {
+        let c = &self.config;
+        let flags = [
+            ("TLS", c.tls), ("MCCP2", c.mccp2), ("MCCP3", c.mccp3), ("GMCP", c.gmcp), ("MSDP", c.msdp),
+            ("MSSP", c.mssp), ("MXP", c.mxp), ("NAWS", c.naws), ("TTYPE", c.ttype), ("SGA", c.sga),
+            ("LINEMODE", c.linemode), ("MNES", c.mnes), ("VT100", c.vt100)
+        ];
+        let enabled: Vec<&str> = flags.iter().filter(|(_, on)| *on).map(|(n, _)| *n).collect();
+        let lines = vec![
+            format!("Client: {} {}", c.client_name, c.client_version),
+            format!("Terminal types: {}", if c.terminal_types.is_empty() { "none".to_string() } else { c.terminal_types.join(", ") }),
+            format!("Address: {} port {}", c.host_address, c.host_port),
+            format!("Color: {}{}", c.color.name(), if self.overrides.color.is_some() { " (forced)" } else { "" }),
+            format!("Screen: {}x{}{}", c.width, c.height,
+                    if self.overrides.width.is_some() || self.overrides.height.is_some() { " (forced)" } else { "" }),
+            format!("Encoding: {}{}", self.charset.name(), if self.overrides.charset.is_some() { " (forced)" } else { "" }),
+            format!("Screen reader: {}{}", if c.screen_reader { "on" } else { "off" }, if self.overrides.screen_reader.is_some() { " (forced)" } else { "" }),
+            format!("Word wrap: {}", if self.wrap { "on" } else { "off" }),
+            format!("Options: {}", if enabled.is_empty() { "none".to_string() } else { enabled.join(" ") })
+        ];
+        for line in lines {
+            self.send_line(&line).await;
+        }
+    }
+
+    async fn show_stats(&mut self) {
+        let st = self.make_stats();
+        let lines = vec![
+            format!("Connected for {} seconds, idle for {} seconds.", st.connected_secs, st.idle_secs),
+            format!("Received {} bytes ({} on the wire).", st.bytes_in, st.wire_bytes_in),
+            format!("Sent {} bytes ({} on the wire).", st.bytes_out, st.wire_bytes_out)
+        ];
+        for line in lines {
+            self.send_line(&line).await;
+        }
     }
 
     async fn process_protocol_message(&mut self, msg: Msg2MudProtocol) {
@@ -342,457 +639,419 @@ impl<T> TelnetProtocol<T> where T: AsyncRead + AsyncWrite + Send + 'static + Unp
             Msg2MudProtocol::Disconnect => {
                 self.running = false;
             },
+            Msg2MudProtocol::RequestStats => {
+                let _ = self.tx_portal.send(Msg2Portal::FromClient(self.conn_id, Msg2PortalFromClient::Stats(self.make_stats()))).await;
+            },
+            Msg2MudProtocol::SetOption(name, value) => {
+                let _ = self.set_option(name, value).await;
+            },
             Msg2MudProtocol::Data(v) => {
+                let mut dropped = Vec::new();
                 for d in v {
-                    let _ = self.process_protocol_message_data(d).await;
+                    if let Some(cmd) = self.process_protocol_message_data(d).await {
+                        dropped.push(cmd);
+                    }
+                }
+                if !dropped.is_empty() {
+                    let _ = self.tx_portal.send(Msg2Portal::FromClient(self.conn_id, Msg2PortalFromClient::OobDropped(dropped))).await;
                 }
             },
         }
     }
 
-    async fn process_protocol_message_data(&mut self, d: MudData) {
-        let mut to_send: Vec<TelnetEvent>  = Vec::new();
-
-        match d.cmd.as_str() {
-            "text" => {
-                // d.args is a Vec<JsonValue> and ideally each JsonValue is a string.
-                // Just send them all as-is. It's up to the game server to handle line splits.
-                for jv in d.args {
-                    if let JsonValue::String(s) = jv {
-                        to_send.push(TelnetEvent::Data(Bytes::from(ensure_crlf(&s))));
-                    }
+    async fn set_option(&mut self, name: String, value: JsonValue) {
+        match name.as_str() {
+            "prompt_terminator" => {
+                if let Some(t) = value.as_str().and_then(PromptTerminator::from_name) {
+                    self.prompt_terminator = t;
                 }
             },
-            "prompt" => {
-                // Prompts are similar to text but end in IAC GA or something to that effect. TODO.
-            }
-            "mssp" => {
-                // This will handle MSSP (Mud Server Status Protocol) data. For this, we need to
-                // extract the key-values from d.kwargs (which must be strings), format them as
-                // a sequence of <key> <value>, join them with newlines, and send them as a
-                // subnegotiation packet IAC SB <MSSP> <lines> IAC SE.
-                let mut mssp_data = Vec::new();
-                for (k, v) in d.kwargs {
-                    mssp_data.push(format!("{} {}", k, v));
+            "wrap" => {
+                if let Some(enable) = value.as_bool() {
+                    self.wrap = enable;
                 }
-                let mssp_data = mssp_data.join("\r\n");
-                to_send.push(TelnetEvent::SubNegotiate(tc::MSSP, Bytes::from(mssp_data)));
             },
-            _ => {
-                // Anything that isn't text, a prompt, or MSSP, is going to be sent as GMCP.
-                // GMCP data is sent via IAC SB <GMCP> <cmd>[ <json>] IAC SE. the json data part is
-                // optional and must be separated from the string <cmd> by a space if present.
-                // Since our MudData struct only has args and kwargs, the json data will be sent
-                // as a list of args and kwargs. So for example, the client might see:
-                // IAC SB GMCP room.data [[], {"name": "The Hall of Limbo", "id": 50}] IAC SE
-                // In this case cmd is room.data, and args was an empty vec, but kwargs had an object.
-                // The empty data structures must always be sent as [] and {} respectively.
-                // For our implementation, the client will ALWAYS be receiving the json even if it's
-                // empty, for consistency's sake.
-                let mut gmcp_data = Vec::new();
-                gmcp_data.push(d.cmd);
-                let mut gmcp_json = Vec::new();
-                // For our process, let's convert args and kwargs to json and send them as a string.
-
-                gmcp_json.push(JsonValue::Array(d.args));
-                gmcp_json.push(JsonValue::Object(d.kwargs.into_iter().map(|(k, v)| (k, v)).collect()));
-                let json_data = JsonValue::Array(gmcp_json);
-
-                gmcp_data.push(json_data.to_string());
-
-                let gmcp_out = gmcp_data.join(" ");
-                to_send.push(TelnetEvent::SubNegotiate(tc::GMCP, Bytes::from(gmcp_out)));
-            }
+            "local_option" | "remote_option" => {
+                // Lets the game switch a telnet option mid-session, e.g.
+                // {"option": 86, "enable": false} to turn MCCP2 off.
+                let op = value["option"].as_u64().and_then(|o| u8::try_from(o).ok());
+                if let (Some(op), Some(enable)) = (op, value["enable"].as_bool()) {
+                    self.request_option(op, name == "local_option", enable).await;
+                }
+            },
+            "secret_input" => {
+                // With WILL ECHO the client stops echoing locally, and since we don't echo
+                // either, nothing the user types shows up.
+                if let Some(enable) = value.as_bool() {
+                    self.secret_input = enable;
+                    self.update_echo().await;
+                }
+            },
+            "character_mode" => {
+                // WILL ECHO and WILL SGA is the classic way of asking a client to send each key
+                // as it's pressed rather than a line at a time.
+                if let Some(enable) = value.as_bool() {
+                    if enable == self.char_mode {
+                        return;
+                    }
+                    self.char_mode = enable;
+                    // Whatever was half-typed in the old mode is dropped.
+                    self.input = LineNormalizer::new(self.portal_config.telnet.line_endings.clone(), self.portal_config.telnet.max_line_length);
+                    self.keys = KeyDecoder::new();
+                    if enable {
+                        self.request_option(tc::SGA, true, true).await;
+                    }
+                    self.update_echo().await;
+                }
+            },
+            _ => {}
         }
+    }
 
-        for te in to_send {
-            if !self.send(te).await {
-                break;
-            }
+    // ECHO is on while either the game hides the user's typing or character mode is on.
+    async fn update_echo(&mut self) {
+        let enable = self.secret_input || self.char_mode;
+        self.request_option(tc::ECHO, true, enable).await;
+    }
+
+    // Asks the client to let us enable or disable an option mid-session, on our side (local) or
+    // theirs. The Q method takes care of requests that cross a negotiation still in flight.
+    async fn request_option(&mut self, op: u8, local: bool, enable: bool) {
+        // Only sides we implement can be switched on, or the client would be told we do
+        // something we don't.
+        let allowed = self.handlers.get(&op).map(|h| h.policy())
+            .map(|p| if local { p.allow_local || p.game_local } else { p.allow_remote })
+            .unwrap_or(false);
+        if enable && !allowed {
+            return;
         }
+        let outcome = match self.op_state.get_mut(&op) {
+            Some(state) if local => state.local.request(enable),
+            Some(state) => state.remote.request(enable),
+            None => return
+        };
+        self.apply_negotiation(op, local, outcome).await;
+    }
 
+    // Sends whatever answer a Q method step calls for and switches the option's feature on or
+    // off to match.
+    async fn apply_negotiation(&mut self, op: u8, local: bool, outcome: QOutcome) {
+        if let Some(positive) = outcome.send {
+            let command = match (local, positive) {
+                (true, true) => tc::WILL,
+                (true, false) => tc::WONT,
+                (false, true) => tc::DO,
+                (false, false) => tc::DONT
+            };
+            self.send(TelnetEvent::Negotiate(command, op)).await;
+        }
+        if let Some(enable) = outcome.changed {
+            self.run_handler(op, |h, ctx| match (local, enable) {
+                (true, true) => h.enable_local(ctx),
+                (true, false) => h.disable_local(ctx),
+                (false, true) => h.enable_remote(ctx),
+                (false, false) => h.disable_remote(ctx)
+            }).await;
+            let _ = self.update_capabilities().await;
+        }
     }
 
-    async fn receive_negotiate(&mut self, command: u8, op: u8) {
-        // This means we received an IAC will/wont/do/dont...
-        let mut handshake: u8 = 0;
-        let mut enable_local = false;
-        let mut disable_local = false;
-        let mut enable_remote = false;
-        let mut disable_remote = false;
-        let mut handshake_remote: u8 = 0;
-        let mut handshake_local: u8 = 0;
-        let mut respond: u8 = 0;
-
-        if let Some(state) = self.op_state.get_mut(&op) {
-            // We DO have a handler for this option... that means we support it!
+    // Calls into an option's handler and then carries out whatever it asked for.
+    async fn run_handler<F>(&mut self, op: u8, f: F) where F: FnOnce(&mut dyn TelnetOptionHandler, &mut OptionContext) {
+        let effects = match self.handlers.get_mut(&op) {
+            Some(handler) => {
+                let mut ctx = OptionContext::new(&mut self.negotiated, &self.portal_config);
+                f(handler.as_mut(), &mut ctx);
+                ctx.into_effects()
+            },
+            None => return
+        };
 
-            match command {
-                tc::WILL => {
-                    // The remote host has sent a WILL. They either want to Locally-Enable op, or are
-                    // doing so at our request.
-                    if !state.remote.enabled {
-                        if state.remote.negotiating {
-                            state.remote.negotiating = false;
-                        }
-                        else {
-                            respond = tc::DO;
-                        }
-                        handshake = op;
-                        handshake_remote = op;
-                        enable_remote = true;
-                        state.remote.enabled = true;
-                    }
-                },
-                tc::WONT => {
-                    // The client has refused an option we wanted to enable. Alternatively, it has
-                    // disabled an option that was on.
-                    if state.remote.negotiating {
-                        handshake = op;
-                        handshake_remote = op;
-                    }
-                    state.remote.negotiating = false;
-                    if state.remote.enabled {
-                        disable_remote = true;
-                        state.remote.enabled = false;
-                    }
-                },
-                tc::DO => {
-                    // The client wants the Server to enable Option, or they are acknowledging our
-                    // desire to do so.
-                    if !state.local.enabled {
-                        if state.local.negotiating {
-                            state.local.negotiating = false;
-                        }
-                        else {
-                            respond = tc::WILL;
-                        }
-                        handshake = op;
-                        handshake_local = op;
-                        enable_local = true;
-                        state.local.enabled = true;
+        let mut to_game = Vec::new();
+        let mut changed = false;
+        for effect in effects {
+            match effect {
+                OptionEffect::Send(te) => {
+                    if !self.send(te).await {
+                        return;
                     }
                 },
-                tc::DONT => {
-                    // The client wants the server to disable Option, or are they are refusing our
-                    // desire to do so.
-                    if state.local.negotiating {
-                        handshake = op;
-                        handshake_local = op;
-                    }
-                    state.local.negotiating = false;
-                    if state.local.enabled {
-                        disable_local = true;
-                        state.local.enabled = false
-                    }
-                },
-                _ => {
-                    // This cannot actually happen.
-                }
+                OptionEffect::ToGame(d) => to_game.push(d),
+                OptionEffect::SetCharset(c) => self.set_charset(c),
+                OptionEffect::StartTls => self.start_tls().await,
+                OptionEffect::AcceptMccp3(accept) => self.conn.codec_mut().set_mccp3_agreed(accept),
+                OptionEffect::CapabilitiesChanged => changed = true
             }
-        } else {
-            // We do not have a handler for this option, whatever it is... do not support.
-            respond = match command {
-                tc::WILL => tc::DONT,
-                tc::DO => tc::WONT,
-                _ => 0
-            };
-        }
-        
-        if respond > 0 {
-            let _ = self.send(TelnetEvent::Negotiate(respond, op)).await;
         }
-        if handshake_local > 0 {
-            self.handshakes_left.local.remove(&handshake_local);
+        if !to_game.is_empty() {
+            let _ = self.tx_portal.send(Msg2Portal::FromClient(self.conn_id, Msg2PortalFromClient::Data(to_game))).await;
         }
-        if handshake_remote > 0 {
-            self.handshakes_left.remote.remove(&handshake_remote);
-        }
-        if enable_local {
-            let _ = self.enable_local(op).await;
-        }
-        if disable_local {
-            let _ = self.disable_local(op).await;
-        }
-        if enable_remote {
-            let _ = self.enable_remote(op).await;
-        }
-        if disable_remote {
-            let _ = self.disable_remote(op).await;
+        if changed {
+            let _ = self.update_capabilities().await;
+        } else {
+            self.apply_overrides();
         }
     }
 
-    async fn enable_remote(&mut self, op: u8) {
-        match op {
-            tc::NAWS => self.config.naws = true,
-            tc::MTTS => {
-                self.handshakes_left.ttype.insert(0);
-                self.request_ttype().await;
-            },
-            tc::LINEMODE => self.config.linemode = true,
-            _ => {
-                // Whatever this option is.. well, whatever.
-            }
-        }
+    fn local_enabled(&self, op: u8) -> bool {
+        self.op_state.get(&op).map(|s| s.local.enabled()).unwrap_or(false)
     }
 
-    async fn disable_remote(&mut self, op: u8) {
-        match op {
-            tc::NAWS => {
-                self.config.naws = false;
-                self.config.width = 78;
-                self.config.height = 24;
-            }
-            tc::MTTS => {
-                self.config.ttype = false;
-                self.handshakes_left.ttype.clear();
-            },
-            tc::LINEMODE => self.config.linemode = false,
-            _ => {
-                // Whatever this option is.. well, whatever.
-            }
+    // The client's width if it told us (or the player forced one), else the configured default.
+    fn wrap_width(&self) -> usize {
+        match self.config.width {
+            w if w > 0 && (self.config.naws || self.overrides.width.is_some()) => w as usize,
+            _ => self.portal_config.telnet.wrap_width as usize
         }
     }
 
-    async fn enable_local(&mut self, op: u8) {
-        match op {
-            tc::SGA => {
-                self.config.sga = true;
-            },
-            tc::MCCP2 => {
-                self.config.mccp2 = true;
-                self.send(TelnetEvent::SubNegotiate(tc::MCCP2, Bytes::new())).await;
-            },
-            tc::MCCP3 => {
-                self.config.mccp3 = true;
-            }
-            _ => {
-                
-            }
+    // Applies the per-message MXP handling shared by text and prompts.
+    fn format_text(&mut self, s: String, mxp_mode: Option<MxpMode>, markup: bool) -> String {
+        // Portal markup becomes ANSI, or plain text for screen readers.
+        let s = match markup {
+            true if self.config.screen_reader => markup::to_plain(&s),
+            true => markup::to_ansi(&s),
+            false => s
+        };
+        let s = match mxp_mode {
+            Some(mode) if self.mxp_active() => mxp::to_mxp(&s, mode),
+            Some(_) => self.mxp.to_plain(&s),
+            None => s
+        };
+        if self.config.screen_reader {
+            return screenreader::text(&s);
         }
+        // The game sends its richest colors; step them down to what this client can show.
+        ansi::downgrade(&s, &self.config.color)
     }
 
-    async fn disable_local(&mut self, op: u8) {
-        match op {
-            tc::SGA => {
-                self.config.sga = false;
-            },
-            _ => {
-
-            }
-        }
+    // MXP markup only goes out to MXP clients, and not in screen reader mode, where the tags
+    // would be read aloud by any client that doesn't render them.
+    fn mxp_active(&self) -> bool {
+        self.config.mxp && !self.config.screen_reader
     }
 
-    async fn receive_sub(&mut self, op: u8, mut data: Bytes) {
-        if !self.op_state.contains_key(&op) {
-            // Only if we can get a handler, do we want to care about this.
-            // All other sub-data is ignored.
-            return;
-        }
+    // Returns the command name if it was OOB data that this client had no way to receive.
+    async fn process_protocol_message_data(&mut self, d: MudData) -> Option<String> {
+        let mut dropped = None;
+        let mut to_send: Vec<TelnetEvent>  = Vec::new();
 
-        match op {
-            tc::NAWS => {
-                let _ = self.receive_naws(data).await;
-            },
-            tc::MTTS => {
-                let _ = self.receive_ttype(data).await;
-            },
-            tc::GMCP => {
-                if let Ok(s) = String::from_utf8(data.to_vec()) {
-                    if s.contains(" ") {
-                        let mut parts = s.splitn(2, " ");
-                        if let Some(cmd) = parts.next() {
-                            if let Some(data) = parts.next() {
-
-                                let d = MudData {
-                                    cmd: cmd.to_string(),
-                                    args: vec![JsonValue::from(data)],
-                                    kwargs: HashMap::new()
-                                };
-                                let m = Msg2PortalFromClient::Data(vec![d]);
-                                let _ = self.tx_portal.send(Msg2Portal::FromClient(self.conn_id, m)).await;
-                            }
+        match d.cmd.as_str() {
+            "text" => {
+                // d.args is a Vec<JsonValue> and ideally each JsonValue is a string.
+                // Just send them all as-is. It's up to the game server to handle line splits.
+                // If the game marked the text as MXP, it's sent in that line mode to MXP clients
+                // and stripped down to plain text for everyone else.
+                let mxp_mode = d.kwargs.get("mxp").and_then(MxpMode::from_kwarg);
+                let markup = d.kwargs.get("markup").and_then(JsonValue::as_bool).unwrap_or(false);
+                // MXP tags would throw the column count off, so MXP text is left to the client.
+                let wrap = d.kwargs.get("wrap").and_then(JsonValue::as_bool).unwrap_or(self.wrap)
+                    && !(mxp_mode.is_some() && self.mxp_active());
+                for jv in d.args {
+                    if let JsonValue::String(s) = jv {
+                        let mut s = self.format_text(s, mxp_mode, markup);
+                        if wrap {
+                            s = wrap::wrap(&s, self.wrap_width());
                         }
+                        to_send.push(TelnetEvent::Data(self.charset.encode(&ensure_crlf(&s))));
                     }
-                    else {
-                        let d = MudData {
-                            cmd: s,
-                            args: vec![],
-                            kwargs: HashMap::new()
-                        };
-                        let m = Msg2PortalFromClient::Data(vec![d]);
-                        let _ = self.tx_portal.send(Msg2Portal::FromClient(self.conn_id, m)).await;
+                }
+            },
+            "prompt" => {
+                // Prompts are similar to text but never end in a newline. Instead they're marked
+                // with IAC EOR or IAC GA so clients know where the prompt ends.
+                let mxp_mode = d.kwargs.get("mxp").and_then(MxpMode::from_kwarg);
+                let markup = d.kwargs.get("markup").and_then(JsonValue::as_bool).unwrap_or(false);
+                let mut prompt = String::new();
+                for jv in d.args {
+                    if let JsonValue::String(s) = jv {
+                        prompt.push_str(&s);
                     }
                 }
+                let mut prompt = self.format_text(prompt.trim_end_matches(['\r', '\n']).to_string(), mxp_mode, markup);
+                if self.config.screen_reader {
+                    prompt = screenreader::prompt(&prompt);
+                }
+                to_send.push(TelnetEvent::Data(self.charset.encode(&ensure_crlf(&prompt))));
+
+                let terminator = match self.prompt_terminator {
+                    PromptTerminator::Auto if self.local_enabled(tc::TELOPT_EOR) => PromptTerminator::Eor,
+                    PromptTerminator::Auto if !self.config.sga => PromptTerminator::Ga,
+                    PromptTerminator::Auto => PromptTerminator::Nothing,
+                    other => other
+                };
+                match terminator {
+                    PromptTerminator::Eor => to_send.push(TelnetEvent::Command(tc::EOR)),
+                    PromptTerminator::Ga => to_send.push(TelnetEvent::Command(tc::GA)),
+                    _ => {}
+                }
+            }
+            "mssp" => {
+                // The game can still send MSSP variables to one client directly. Crawlers are
+                // normally answered from the portal's cache instead; see send_mssp.
+                if self.config.mssp {
+                    let vars: Vec<(String, Vec<String>)> = d.kwargs.iter()
+                        .map(|(k, v)| (k.to_uppercase(), mssp::value_strings(v)))
+                        .collect();
+                    to_send.push(TelnetEvent::SubNegotiate(tc::MSSP, mssp::encode(&vars)));
+                }
             },
-            _ => {}
+            _ => {
+                // Anything that isn't text, a prompt, or MSSP, is going to be sent out of band.
+                // That means GMCP if the client agreed to it, then MSDP, then a text fallback.
+                match oob::route(&self.config, &self.portal_config.oob, &d) {
+                    OobRoute::Gmcp => to_send.push(TelnetEvent::SubNegotiate(tc::GMCP, gmcp::encode(&d))),
+                    OobRoute::Msdp => to_send.push(TelnetEvent::SubNegotiate(tc::MSDP, msdp::encode(&d))),
+                    OobRoute::Text(s) => to_send.push(TelnetEvent::Data(self.charset.encode(&ensure_crlf(&s)))),
+                    OobRoute::Json | OobRoute::Drop => dropped = Some(d.cmd)
+                }
+            }
         }
-    }
-
-    async fn request_ttype(&mut self) {
-        let mut data = BytesMut::with_capacity(1);
-        data.put_u8(1);
-        self.send(TelnetEvent::SubNegotiate(tc::MTTS, data.freeze())).await;
-    }
-
-    async fn receive_ttype(&mut self, mut data: Bytes) {
 
-        if data.len() < 2 {
-            return
+        for te in to_send {
+            if !self.send(te).await {
+                break;
+            }
         }
 
-        if self.handshakes_left.ttype.is_empty() {
-            return;
-        }
+        dropped
+    }
 
-        if data[0] != 0 {
-            return;
-        }
+    async fn receive_negotiate(&mut self, command: u8, op: u8) {
+        // This means we received an IAC will/wont/do/dont...
+        let local = matches!(command, tc::DO | tc::DONT);
+        let agree = self.handlers.get(&op).map(|h| {
+            let policy = h.policy();
+            if local { policy.allow_local } else { policy.allow_remote }
+        }).unwrap_or(false);
 
-        data.advance(1);
+        let Some(state) = self.op_state.get_mut(&op) else {
+            // We do not have a handler for this option, whatever it is... do not support.
+            // Refusals of something we never offered need no answer.
+            match command {
+                tc::WILL => { self.send(TelnetEvent::Negotiate(tc::DONT, op)).await; },
+                tc::DO => { self.send(TelnetEvent::Negotiate(tc::WONT, op)).await; },
+                _ => {}
+            }
+            return;
+        };
 
-        if let Ok(s) = String::from_utf8(data.to_vec()) {
-            let upper = s.trim().to_uppercase();
+        let side = if local { &mut state.local } else { &mut state.remote };
+        let outcome = match command {
+            tc::WILL | tc::DO => side.receive_yes(agree),
+            _ => side.receive_no()
+        };
+        let settled = !side.pending();
 
-            match self.ttype_count {
-                0 => {
-                    self.ttype_last = Some(upper.clone());
-                    let _ = self.receive_ttype_0(upper.clone()).await;
-                    self.ttype_count += 1;
-                    self.handshakes_left.ttype.remove(&0);
-                    let _ = self.request_ttype().await;
-                    return;
-                },
-                1 | 2 => {
-                    if let Some(last) = self.ttype_last.clone() {
-                        if last.eq(&upper) {
-                            // This client does not support advanced ttype. Ignore further
-                            // calls to TTYPE and consider this complete.
-                            self.handshakes_left.ttype.clear();
-                            self.ttype_last = None;
-                        } else {
-                            match self.ttype_count {
-                                1 => {
-                                    let _ = self.receive_ttype_1(upper.clone()).await;
-                                    self.ttype_last = Some(upper.clone());
-                                },
-                                2 => {
-                                    let _ = self.receive_ttype_2(upper.clone()).await;
-                                    self.ttype_last = None;
-                                    self.handshakes_left.ttype.clear();
-                                }
-                                _ => {}
-                            }
-                        }
-                    }
-                    return;
-                }
-                _ => {
-                    unreachable!("TTYPE count is out of bounds.");
-                }
+        // Whatever we asked for during the opening handshake has been answered once the side
+        // is no longer waiting.
+        if settled {
+            if local {
+                self.handshakes_left.local.remove(&op);
+            } else {
+                self.handshakes_left.remote.remove(&op);
             }
         }
+        self.apply_negotiation(op, local, outcome).await;
+    }
 
-
+    async fn receive_sub(&mut self, op: u8, data: Bytes) {
+        // Sub-data for options without a handler is ignored.
+        self.run_handler(op, |h, ctx| h.subnegotiate(ctx, data)).await;
     }
 
-    async fn receive_ttype_0(&mut self, data: String) {
-        // The first TTYPE receives the name of the client.
-        // version might also be in here as a second word.
-        if data.contains(" ") {
-            let results: Vec<&str> = data.splitn(2, " ").collect();
-            if results.len() > 0 {
-                self.config.client_name = String::from(results[0]);
-            }
-            if results.len() > 1 {
-                self.config.client_version = String::from(results[1]);
-            }
-        } else {
-            self.config.client_name = data;
-        }
+    async fn start_tls(&mut self) {
+        let acceptor = match self.starttls.take() {
+            Some(a) => a,
+            None => return
+        };
+
+        // Anything already read past IAC SB START_TLS FOLLOWS IAC SE is the start of the TLS
+        // handshake, so it has to be handed back to the stream.
+        let mut pending = self.conn.codec_mut().take_inbound();
+        pending.extend_from_slice(&self.conn.read_buffer_mut().split());
 
-        // Now that the name and version (may be UNKNOWN) are set... we can deduce capabilities.
-        let mut extra_check = false;
-        match self.config.client_name.as_str() {
-            "ATLANTIS" | "CMUD"  | "KILDCLIENT" | "MUDLET" | "MUSHCLIENT"  | "PUTTY" | "BEIP" | "POTATO" | "TINYFUGUE" => {
-                self.config.color = Color::Xterm256;
+        match self.conn.get_mut().start_tls(&acceptor, pending).await {
+            Ok(()) => {
+                self.negotiated.tls = true;
+                self.negotiated.encryption = true;
+                let _ = self.update_capabilities().await;
             },
-            _ => {
-                extra_check = true;
-            }
-        }
-        if extra_check {
-            if (self.config.client_name.starts_with("XTERM") || self.config.client_name.ends_with("-256COLOR")) && self.config.color != Color::TrueColor {
-                self.config.color = Color::Xterm256;
+            Err(e) => {
+                self.disconnect(format!("STARTTLS failed: {}", e)).await;
             }
         }
     }
 
-    async fn receive_ttype_1(&mut self, data: String) {
-        if (data.starts_with("XTERM") || data.ends_with("-256COLOR")) && self.config.color != Color::TrueColor  {
-            self.config.color = Color::Xterm256;
-        }
-        self.handshakes_left.ttype.remove(&1);
+    fn offered_charsets(&self) -> Vec<Charset> {
+        handlers::offered_charsets(&self.portal_config)
     }
 
-    async fn receive_ttype_2(&mut self, data: String) {
-        if !data.starts_with("MTTS ") {
-            return;
-        }
-        let results: Vec<&str> = data.splitn(2, " ").collect();
-        let value = String::from(results[1]);
-        let mtts: usize = value.parse().unwrap_or(0);
-        if mtts == 0 {
-            return;
-        }
-        if (1 & mtts) == 1 && (self.config.color.clone() as i32) < Color::Standard as i32 {
-            self.config.color = Color::Standard;
-        }
+    fn set_charset(&mut self, c: Charset) {
+        self.negotiated_charset = c;
+        self.negotiated.encoding = c.name().to_string();
+        self.negotiated.utf8 = c == Charset::Utf8;
+        self.apply_overrides();
+    }
 
-        if (2 & mtts) == 2 {
-            self.config.vt100 = true;
-        }
-        if (4 & mtts) == 4 {
-            self.config.utf8 = true;
-        }
-        if (8 & mtts) == 8 && (self.config.color.clone() as i32) < Color::Xterm256 as i32 {
-            self.config.color = Color::Xterm256;
-        }
-        if (16 & mtts) == 16 {
-            self.config.mouse_tracking = true;
-        }
-        if (32 & mtts) == 32 {
-            self.config.osc_color_palette = true;
+    // Rebuilds config and charset from what was negotiated, so clearing an override brings the
+    // negotiated value straight back.
+    fn apply_overrides(&mut self) {
+        self.config = self.negotiated.clone();
+        self.charset = self.negotiated_charset;
+        if let Some(c) = self.overrides.color.clone() {
+            self.config.color = c;
         }
-        if (64 & mtts) == 64 {
-            self.config.screen_reader = true;
+        if let Some(w) = self.overrides.width {
+            self.config.width = w;
         }
-        if (128 & mtts) == 128 {
-            self.config.proxy = true;
+        if let Some(h) = self.overrides.height {
+            self.config.height = h;
         }
-        if (256 & mtts) == 256  && (self.config.color.clone() as i32) < Color::TrueColor as i32 {
-            self.config.color = Color::TrueColor;
+        if let Some(c) = self.overrides.charset {
+            self.charset = c;
+            self.config.encoding = c.name().to_string();
+            self.config.utf8 = c == Charset::Utf8;
         }
-        if (512 & mtts) == 512 {
-            self.config.mnes = true;
+        if let Some(enable) = self.overrides.screen_reader {
+            self.config.screen_reader = enable;
         }
-        self.handshakes_left.ttype.remove(&2);
     }
 
-    async fn receive_naws(&mut self, mut data: Bytes) {
+    // Crawlers without telnet negotiation send MSSP-REQUEST as soon as they connect. They get
+    // a plain-text reply and are disconnected without the game ever seeing them.
+    // Crawlers that use DO MSSP get their answer straight away and mostly hang up, but some stay
+    // connected past negotiation. Until a client either types something or agrees to any option
+    // besides MSSP, it is held back from the game. Real clients that ask for MSSP also take up
+    // TTYPE, NAWS and the like, so they aren't held up.
+    fn looks_like_crawler(&self) -> bool {
+        self.local_enabled(tc::MSSP) && self.app_buffer.is_empty()
+            && !self.op_state.iter().any(|(op, s)| *op != tc::MSSP && (s.local.enabled() || s.remote.enabled()))
+    }
 
-        if data.len() >= 4 {
-            let old_width = self.config.width;
-            let old_height = self.config.height;
-            self.config.width = data.get_u16();
-            self.config.height = data.get_u16();
-            if (self.config.width != old_width) || (self.config.height != old_height) {
-                let _ = self.update_capabilities().await;
-            }
+    async fn check_mssp_request(&mut self) {
+        let line = match self.app_buffer.iter().position(|b| *b == b'\n' || *b == b'\r') {
+            Some(pos) => String::from_utf8_lossy(&self.app_buffer[..pos]).trim().to_string(),
+            None => return
+        };
+        if line != mssp::REQUEST_LINE {
+            return;
         }
+
+        self.crawler = true;
+        self.app_buffer.clear();
+        let vars = MSSP.lock().unwrap().variables(&self.portal_config.mssp);
+        self.send(TelnetEvent::Data(Bytes::from(mssp::encode_text(&vars)))).await;
+        self.running = false;
     }
 
     async fn update_capabilities(&mut self) {
+        for (op, handler) in self.handlers.iter() {
+            let (local, remote) = self.op_state.get(op).map(|s| (s.local.enabled(), s.remote.enabled())).unwrap_or((false, false));
+            handler.capabilities(local, remote, &mut self.negotiated);
+        }
+        self.negotiated.oob = self.negotiated.gmcp || self.negotiated.msdp;
+        self.apply_overrides();
         if self.sent_link {
             let _ = self.tx_portal.send(Msg2Portal::FromClient(self.conn_id, Msg2PortalFromClient::Capabilities(self.config.clone()))).await;
         }
@@ -0,0 +1,305 @@
+// Telnet option negotiation by the Q method of RFC 1143. Each option has two sides: local (what
+// we do, negotiated with WILL/WONT and answered with DO/DONT) and remote (what the client does,
+// the other way around). Each side is in one of four states, plus a one-deep queue that
+// remembers the opposite request made while a negotiation was still in flight. Following it
+// exactly means we never answer an answer, so a client can't drag us into a negotiation loop,
+// and we never lose track of where an option stands.
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QState {
+    #[default]
+    No,
+    Yes,
+    // We asked to disable and are waiting for the answer.
+    WantNo,
+    // We asked to enable and are waiting for the answer.
+    WantYes
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QQueue {
+    #[default]
+    Empty,
+    // Once the current negotiation ends, ask for the opposite.
+    Opposite
+}
+
+// What the caller has to do after a step: send the positive (WILL/DO) or negative (WONT/DONT)
+// command, and/or switch the option's feature on or off.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QOutcome {
+    pub send: Option<bool>,
+    pub changed: Option<bool>
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QSide {
+    pub state: QState,
+    pub queue: QQueue
+}
+
+impl QSide {
+    pub fn enabled(&self) -> bool {
+        self.state == QState::Yes
+    }
+
+    // True while we're waiting on the other end.
+    pub fn pending(&self) -> bool {
+        matches!(self.state, QState::WantNo | QState::WantYes)
+    }
+
+    fn outcome(&self, was_enabled: bool, send: Option<bool>) -> QOutcome {
+        let changed = if self.enabled() != was_enabled { Some(self.enabled()) } else { None };
+        QOutcome { send, changed }
+    }
+
+    // The other end sent WILL (remote side) or DO (local side). agree says whether we're willing
+    // to have the option on at all.
+    pub fn receive_yes(&mut self, agree: bool) -> QOutcome {
+        let was = self.enabled();
+        let send = match (self.state, self.queue) {
+            (QState::No, _) => {
+                if agree {
+                    self.state = QState::Yes;
+                    Some(true)
+                } else {
+                    Some(false)
+                }
+            },
+            (QState::Yes, _) => None,
+            // Our disable was answered with an enable, which isn't allowed. Call it off.
+            (QState::WantNo, QQueue::Empty) => {
+                self.state = QState::No;
+                None
+            },
+            (QState::WantNo, QQueue::Opposite) => {
+                self.state = QState::Yes;
+                self.queue = QQueue::Empty;
+                None
+            },
+            (QState::WantYes, QQueue::Empty) => {
+                self.state = QState::Yes;
+                None
+            },
+            (QState::WantYes, QQueue::Opposite) => {
+                self.state = QState::WantNo;
+                self.queue = QQueue::Empty;
+                Some(false)
+            }
+        };
+        self.outcome(was, send)
+    }
+
+    // The other end sent WONT (remote side) or DONT (local side).
+    pub fn receive_no(&mut self) -> QOutcome {
+        let was = self.enabled();
+        let send = match (self.state, self.queue) {
+            (QState::No, _) => None,
+            (QState::Yes, _) => {
+                self.state = QState::No;
+                Some(false)
+            },
+            (QState::WantNo, QQueue::Empty) => {
+                self.state = QState::No;
+                None
+            },
+            (QState::WantNo, QQueue::Opposite) => {
+                self.state = QState::WantYes;
+                self.queue = QQueue::Empty;
+                Some(true)
+            },
+            (QState::WantYes, _) => {
+                self.state = QState::No;
+                self.queue = QQueue::Empty;
+                None
+            }
+        };
+        self.outcome(was, send)
+    }
+
+    // We want the option on or off. Asking for what's already the case, or already queued, does
+    // nothing. A side being disabled counts as off from the moment we ask.
+    pub fn request(&mut self, enable: bool) -> QOutcome {
+        let was = self.enabled();
+        let send = match (self.state, self.queue, enable) {
+            (QState::No, _, true) => {
+                self.state = QState::WantYes;
+                Some(true)
+            },
+            (QState::Yes, _, false) => {
+                self.state = QState::WantNo;
+                Some(false)
+            },
+            (QState::WantNo, QQueue::Empty, true) | (QState::WantYes, QQueue::Empty, false) => {
+                self.queue = QQueue::Opposite;
+                None
+            },
+            (QState::WantNo, QQueue::Opposite, false) | (QState::WantYes, QQueue::Opposite, true) => {
+                self.queue = QQueue::Empty;
+                None
+            },
+            _ => None
+        };
+        self.outcome(was, send)
+    }
+}
+
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QOption {
+    pub local: QSide,
+    pub remote: QSide
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use QQueue::*;
+    use QState::*;
+
+    #[derive(Debug, Clone, Copy)]
+    enum Event {
+        // WILL or DO from the other end, and whether we agree to it.
+        Yes(bool),
+        // WONT or DONT from the other end.
+        No,
+        // We want the option on or off.
+        Request(bool)
+    }
+
+    fn step(side: &mut QSide, event: Event) -> QOutcome {
+        match event {
+            Event::Yes(agree) => side.receive_yes(agree),
+            Event::No => side.receive_no(),
+            Event::Request(enable) => side.request(enable)
+        }
+    }
+
+    // Starting state and queue, event, resulting state and queue, and what gets sent (true for
+    // WILL/DO, false for WONT/DONT).
+    type Row = (QState, QQueue, Event, QState, QQueue, Option<bool>);
+
+    // Every row of the tables in RFC 1143 section 7.
+    const TABLE: &[Row] = &[
+        (No, Empty, Event::Yes(true), Yes, Empty, Some(true)),
+        (No, Empty, Event::Yes(false), No, Empty, Some(false)),
+        (Yes, Empty, Event::Yes(true), Yes, Empty, None),
+        (WantNo, Empty, Event::Yes(true), No, Empty, None),
+        (WantNo, Opposite, Event::Yes(true), Yes, Empty, None),
+        (WantYes, Empty, Event::Yes(true), Yes, Empty, None),
+        (WantYes, Opposite, Event::Yes(true), WantNo, Empty, Some(false)),
+
+        (No, Empty, Event::No, No, Empty, None),
+        (Yes, Empty, Event::No, No, Empty, Some(false)),
+        (WantNo, Empty, Event::No, No, Empty, None),
+        (WantNo, Opposite, Event::No, WantYes, Empty, Some(true)),
+        (WantYes, Empty, Event::No, No, Empty, None),
+        (WantYes, Opposite, Event::No, No, Empty, None),
+
+        (No, Empty, Event::Request(true), WantYes, Empty, Some(true)),
+        (Yes, Empty, Event::Request(true), Yes, Empty, None),
+        (WantNo, Empty, Event::Request(true), WantNo, Opposite, None),
+        (WantNo, Opposite, Event::Request(true), WantNo, Opposite, None),
+        (WantYes, Empty, Event::Request(true), WantYes, Empty, None),
+        (WantYes, Opposite, Event::Request(true), WantYes, Empty, None),
+
+        (No, Empty, Event::Request(false), No, Empty, None),
+        (Yes, Empty, Event::Request(false), WantNo, Empty, Some(false)),
+        (WantNo, Empty, Event::Request(false), WantNo, Empty, None),
+        (WantNo, Opposite, Event::Request(false), WantNo, Empty, None),
+        (WantYes, Empty, Event::Request(false), WantYes, Opposite, None),
+        (WantYes, Opposite, Event::Request(false), WantYes, Opposite, None),
+    ];
+
+    #[test]
+    fn rfc1143_transitions() {
+        for &(state, queue, event, next, next_queue, send) in TABLE {
+            let mut side = QSide { state, queue };
+            let was = side.enabled();
+            let outcome = step(&mut side, event);
+            let label = format!("{:?}/{:?} on {:?}", state, queue, event);
+            assert_eq!((side.state, side.queue), (next, next_queue), "{}", label);
+            assert_eq!(outcome.send, send, "{}", label);
+            let changed = if side.enabled() != was { Some(side.enabled()) } else { None };
+            assert_eq!(outcome.changed, changed, "{}", label);
+        }
+    }
+
+    // Plays our side against a client that answers every one of our commands with the given
+    // reply, whether or not it's an answer to an answer. Returns how many commands we sent after
+    // the first. Anything but zero would mean we answered an answer.
+    fn against(side: &mut QSide, first: Option<bool>, reply: impl Fn(bool) -> Option<bool>) -> usize {
+        let mut pending = first;
+        let mut extra = 0;
+        let mut rounds = 0;
+        while let Some(sent) = pending.take() {
+            rounds += 1;
+            assert!(rounds < 10, "negotiation loop");
+            pending = match reply(sent) {
+                Some(true) => side.receive_yes(true).send,
+                Some(false) => side.receive_no().send,
+                None => None
+            };
+            if pending.is_some() {
+                extra += 1;
+            }
+        }
+        extra
+    }
+
+    #[test]
+    fn client_that_always_agrees() {
+        // DO is always met with WILL and DONT with WONT.
+        let agree = |positive: bool| Some(positive);
+        let mut side = QSide::default();
+
+        let first = side.request(true).send;
+        assert_eq!(against(&mut side, first, agree), 0);
+        assert_eq!(side.state, Yes);
+
+        let first = side.request(false).send;
+        assert_eq!(against(&mut side, first, agree), 0);
+        assert_eq!(side.state, No);
+
+        // Changing our mind mid-negotiation gets exactly one more command, not a loop.
+        let first = side.request(true).send;
+        assert_eq!(side.request(false).send, None);
+        assert_eq!(against(&mut side, first, agree), 1);
+        assert_eq!((side.state, side.queue), (No, Empty));
+    }
+
+    #[test]
+    fn repeated_yes_while_enabled() {
+        let mut side = QSide { state: Yes, queue: Empty };
+        for _ in 0..5 {
+            assert_eq!(side.receive_yes(true), QOutcome::default());
+        }
+        assert!(side.enabled());
+    }
+
+    #[test]
+    fn refusal_crossing_our_request() {
+        // We sent WILL, then decided against it before the answer came. The client's DONT
+        // crosses our WILL: we end up off, and nothing is sent in reply.
+        let mut side = QSide::default();
+        assert_eq!(side.request(true).send, Some(true));
+        assert_eq!(side.request(false).send, None);
+        assert_eq!(side.queue, Opposite);
+
+        let outcome = side.receive_no();
+        assert_eq!(outcome, QOutcome::default());
+        assert_eq!((side.state, side.queue), (No, Empty));
+    }
+
+    #[test]
+    fn two_q_method_ends_agree() {
+        // Both ends ask for the option at the same moment. Each takes the other's request as the
+        // answer to its own and neither replies.
+        let mut ours = QSide::default();
+        let mut theirs = QSide::default();
+        assert_eq!(ours.request(true).send, Some(true));
+        assert_eq!(theirs.request(true).send, Some(true));
+        assert_eq!(ours.receive_yes(true).send, None);
+        assert_eq!(theirs.receive_yes(true).send, None);
+        assert!(ours.enabled() && theirs.enabled());
+    }
+}
@@ -0,0 +1,129 @@
+use std::{
+    error::Error,
+    fs
+};
+
+use serde::{Serialize, Deserialize};
+
+use crate::protocols::{Color, ProtocolCapabilities};
+
+// Plenty of clients can do more than they say over TTYPE/MTTS. The quirks file (telnet.quirks_file)
+// lists them, so a new client can be supported by editing it and restarting the portal. Each
+// [[quirk]] has patterns for the client name, version and/or terminal type, and the capabilities
+// to give a client that matches all of its patterns.
+//
+// Patterns are case-insensitive, with * matching any run of characters and ? any one character.
+// A missing pattern matches anything.
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub struct ClientQuirk {
+    pub name: Option<String>,
+    pub version: Option<String>,
+    // Matched against every terminal type the client reported.
+    pub terminal: Option<String>,
+    // The least color depth the client is known to handle. Never lowers what it reported itself.
+    pub color: Option<String>,
+    pub utf8: Option<bool>,
+    pub vt100: Option<bool>,
+    pub mouse_tracking: Option<bool>,
+    pub osc_color_palette: Option<bool>,
+    pub screen_reader: Option<bool>,
+    pub force_endline: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+struct QuirksFile {
+    quirk: Vec<ClientQuirk>
+}
+
+// Used when there is no quirks file. The same clients quirks.toml ships with.
+pub fn defaults() -> Vec<ClientQuirk> {
+    let mut out: Vec<ClientQuirk> = ["ATLANTIS", "CMUD", "KILDCLIENT", "MUDLET", "MUSHCLIENT", "PUTTY", "BEIP", "POTATO", "TINYFUGUE"]
+        .iter()
+        .map(|name| ClientQuirk { name: Some(name.to_string()), color: Some("256".to_string()), ..Default::default() })
+        .collect();
+    for terminal in ["XTERM*", "*-256COLOR"] {
+        out.push(ClientQuirk { terminal: Some(terminal.to_string()), color: Some("256".to_string()), ..Default::default() });
+    }
+    out
+}
+
+pub fn load(path: &str) -> Result<Vec<ClientQuirk>, Box<dyn Error>> {
+    let data = fs::read_to_string(path)?;
+    let file: QuirksFile = toml::from_str(&data)?;
+    for quirk in file.quirk.iter() {
+        if let Some(color) = &quirk.color {
+            if Color::from_name(color).is_none() {
+                return Err(format!("unknown color '{}' in quirk for {:?}", color, quirk.name).into());
+            }
+        }
+    }
+    Ok(file.quirk)
+}
+
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let p: Vec<char> = pattern.to_uppercase().chars().collect();
+    let t: Vec<char> = text.to_uppercase().chars().collect();
+    let (mut pi, mut ti) = (0, 0);
+    // Where the last * was, and how much of the text it has swallowed so far.
+    let mut star: Option<(usize, usize)> = None;
+
+    while ti < t.len() {
+        if pi < p.len() && (p[pi] == '?' || p[pi] == t[ti]) {
+            pi += 1;
+            ti += 1;
+        } else if pi < p.len() && p[pi] == '*' {
+            star = Some((pi, ti));
+            pi += 1;
+        } else if let Some((sp, st)) = star {
+            // Let the * take one more character and try again from there.
+            pi = sp + 1;
+            ti = st + 1;
+            star = Some((sp, st + 1));
+        } else {
+            return false;
+        }
+    }
+    p[pi..].iter().all(|c| *c == '*')
+}
+
+impl ClientQuirk {
+    pub fn matches(&self, caps: &ProtocolCapabilities) -> bool {
+        let name = self.name.as_deref().is_none_or(|p| glob_match(p, &caps.client_name));
+        let version = self.version.as_deref().is_none_or(|p| glob_match(p, &caps.client_version));
+        let terminal = self.terminal.as_deref().is_none_or(|p| caps.terminal_types.iter().any(|t| glob_match(p, t)));
+        name && version && terminal
+    }
+
+    pub fn apply(&self, caps: &mut ProtocolCapabilities) {
+        if let Some(color) = self.color.as_deref().and_then(Color::from_name) {
+            if (caps.color.clone() as i32) < (color.clone() as i32) {
+                caps.color = color;
+            }
+        }
+        let flags = [
+            (self.utf8, &mut caps.utf8),
+            (self.vt100, &mut caps.vt100),
+            (self.mouse_tracking, &mut caps.mouse_tracking),
+            (self.osc_color_palette, &mut caps.osc_color_palette),
+            (self.screen_reader, &mut caps.screen_reader),
+            (self.force_endline, &mut caps.force_endline)
+        ];
+        for (value, flag) in flags {
+            if let Some(value) = value {
+                *flag = value;
+            }
+        }
+    }
+}
+
+// Applies every quirk the client matches, in file order.
+pub fn apply_all(quirks: &[ClientQuirk], caps: &mut ProtocolCapabilities) {
+    for quirk in quirks {
+        if quirk.matches(caps) {
+            quirk.apply(caps);
+        }
+    }
+}
@@ -28,12 +28,18 @@ use once_cell::sync::Lazy;
 
 use crate::{
     protocols::{
-        {ProtocolCapabilities, Color, ProtocolLink, MudData}
+        markup,
+        mxp::MxpTranslator,
+        screenreader,
+        oob::{self, OobRoute},
+        {ProtocolCapabilities, Color, ConnectionStats, ProtocolLink, MudData}
     },
+    config::Config,
     msg::{Msg2MudProtocol, Msg2Portal, Msg2PortalFromClient},
     util::ensure_crlf,
     IS_TLS_ENABLED,
-    TX_PORTAL
+    TX_PORTAL,
+    CONFIG
 };
 
 use warp::ws::{WebSocket, Message};
@@ -48,7 +54,11 @@ pub struct WebsocketProtocol {
     running: bool,
     time_created: Instant,
     time_activity: Instant,
-    conn: WebSocket
+    conn: WebSocket,
+    portal_config: Config,
+    bytes_in: u64,
+    bytes_out: u64,
+    mxp: MxpTranslator
 }
 
 impl WebsocketProtocol {
@@ -66,7 +76,11 @@ impl WebsocketProtocol {
             running: false,
             time_created: Instant::now(),
             time_activity: Instant::now(),
-            conn
+            conn,
+            portal_config: CONFIG.lock().unwrap().clone(),
+            bytes_in: 0,
+            bytes_out: 0,
+            mxp: Default::default()
         };
 
         out.config.protocol = Protocol::WebSocket;
@@ -77,6 +91,8 @@ impl WebsocketProtocol {
         out.config.client_name = "Thermite Webclient".to_string();
         out.config.client_version = "0.1".to_string();
         out.config.utf8 = true;
+        // The bundled webclient handles OOB until its options say otherwise.
+        out.config.oob = true;
 
         out
 
@@ -129,10 +145,51 @@ impl WebsocketProtocol {
             Msg2MudProtocol::Disconnect => {
                 self.running = false;
             },
+            Msg2MudProtocol::SetOption(name, value) => {
+                let _ = self.set_option(name, value).await;
+            },
+            Msg2MudProtocol::RequestStats => {
+                // Websocket compression (if any) is handled by warp, so we only see raw sizes.
+                let stats = ConnectionStats {
+                    connected_secs: self.time_created.elapsed().as_secs(),
+                    idle_secs: self.time_activity.elapsed().as_secs(),
+                    bytes_in: self.bytes_in,
+                    bytes_out: self.bytes_out,
+                    wire_bytes_in: self.bytes_in,
+                    wire_bytes_out: self.bytes_out
+                };
+                let _ = self.tx_portal.send(Msg2Portal::FromClient(self.conn_id, Msg2PortalFromClient::Stats(stats))).await;
+            },
             Msg2MudProtocol::Data(v) => {
+                let mut dropped = Vec::new();
                 for d in v {
-                    let _ = self.process_protocol_message_data(d).await;
+                    if let Some(cmd) = self.process_protocol_message_data(d).await {
+                        dropped.push(cmd);
+                    }
+                }
+                if !dropped.is_empty() {
+                    let _ = self.tx_portal.send(Msg2Portal::FromClient(self.conn_id, Msg2PortalFromClient::OobDropped(dropped))).await;
+                }
+            }
+        }
+    }
+
+    async fn set_option(&mut self, name: String, value: JsonValue) {
+        match name.as_str() {
+            "secret_input" => {
+                // The bundled webclient masks its input field when it gets this.
+                if let Some(enable) = value.as_bool() {
+                    let _ = self.process_protocol_message_data(MudData {
+                        cmd: "secret_input".to_string(),
+                        args: vec![JsonValue::Bool(enable)],
+                        kwargs: Default::default()
+                    }).await;
+                    self.config.secret_input = enable;
+                    let _ = self.tx_portal.send(Msg2Portal::FromClient(self.conn_id, Msg2PortalFromClient::Capabilities(self.config.clone()))).await;
                 }
+            },
+            _ => {
+                // The remaining options are about telnet presentation and don't apply here.
             }
         }
     }
@@ -141,6 +198,8 @@ impl WebsocketProtocol {
         if let Some(msg) = t_msg {
             match msg {
                 Ok(msg) => {
+                    self.time_activity = Instant::now();
+                    self.bytes_in += msg.as_bytes().len() as u64;
                     if msg.is_text() {
                         let _ = self.handle_text_message(msg.to_str().unwrap()).await;
                     } else if msg.is_binary() {
@@ -165,10 +224,58 @@ impl WebsocketProtocol {
         }
     }
 
-    async fn process_protocol_message_data(&mut self, d: MudData) {
+    // Returns the command name if it was OOB data that this client had no way to receive.
+    async fn process_protocol_message_data(&mut self, d: MudData) -> Option<String> {
         // One of the great things about the webclient is...
         // IT will handle this. We don't need to do anything. Much.
 
+        // OOB commands go through the same routing as telnet, so a webclient that turned OOB off
+        // in its options gets the same text fallbacks.
+        let d = match d.cmd.as_str() {
+            "text" | "prompt" if d.kwargs.contains_key("mxp") || d.kwargs.contains_key("markup") || self.config.screen_reader => {
+                // The webclient doesn't speak MXP, so marked text is stripped to plain text.
+                // Portal markup becomes HTML spans, or plain text for screen readers.
+                let mxp = d.kwargs.contains_key("mxp");
+                let markup = d.kwargs.get("markup").and_then(JsonValue::as_bool).unwrap_or(false);
+                let screen_reader = self.config.screen_reader;
+                let prompt = d.cmd == "prompt";
+                let args = d.args.iter().map(|jv| match jv {
+                    JsonValue::String(s) => {
+                        let s = if mxp { self.mxp.to_plain(s) } else { s.clone() };
+                        let s = match markup {
+                            true if screen_reader => markup::to_plain(&s),
+                            true => markup::to_html(&s),
+                            false => s
+                        };
+                        JsonValue::String(match (screen_reader, prompt) {
+                            (true, true) => screenreader::prompt(&s),
+                            (true, false) => screenreader::text(&s),
+                            _ => s
+                        })
+                    },
+                    other => other.clone()
+                }).collect();
+                let mut kwargs = d.kwargs;
+                kwargs.remove("mxp");
+                kwargs.remove("markup");
+                MudData {
+                    cmd: d.cmd,
+                    args,
+                    kwargs
+                }
+            },
+            "text" | "prompt" => d,
+            _ => match oob::route(&self.config, &self.portal_config.oob, &d) {
+                OobRoute::Text(s) => MudData {
+                    cmd: "text".to_string(),
+                    args: vec![JsonValue::String(s)],
+                    kwargs: Default::default()
+                },
+                OobRoute::Drop => return Some(d.cmd),
+                _ => d
+            }
+        };
+
         // Serialize d using serde into a json object and send it out as text.
         // we must create a new JSON Array and send the data like this:
         // [d.cmd, d.args, d.kwargs]
@@ -183,11 +290,13 @@ impl WebsocketProtocol {
             Ok(s) => s,
             Err(e) => {
                 eprintln!("Failed to serialize data into JSON: {}", e);
-                return;
+                return None;
             }
         };
 
+        self.bytes_out += data_string.len() as u64;
         let _ = self.conn.send(Message::text(data_string)).await;
+        None
 
     }
 
@@ -195,6 +304,22 @@ impl WebsocketProtocol {
         // Any text message we receive should be a json object that can become a MudData.
         // Deserialize it and send it to the portal.
         if let Ok(d) = serde_json::from_str::<MudData>(s) {
+            // The webclient's options panel reports all of its settings at once. Screen reader
+            // mode and OOB are ours to apply; the game still gets the whole message.
+            if d.cmd == "webclient_options" {
+                let mut changed = false;
+                if let Some(enable) = d.kwargs.get("screenreader").and_then(JsonValue::as_bool) {
+                    changed |= enable != self.config.screen_reader;
+                    self.config.screen_reader = enable;
+                }
+                if let Some(enable) = d.kwargs.get("oob").and_then(JsonValue::as_bool) {
+                    changed |= enable != self.config.oob;
+                    self.config.oob = enable;
+                }
+                if changed {
+                    let _ = self.tx_portal.send(Msg2Portal::FromClient(self.conn_id, Msg2PortalFromClient::Capabilities(self.config.clone()))).await;
+                }
+            }
             let _ = self.tx_portal.send(Msg2Portal::FromClient(self.conn_id, Msg2PortalFromClient::Data(vec![d]))).await;
         }
 
@@ -0,0 +1,204 @@
+use unicode_width::UnicodeWidthChar;
+
+// Word wrapping for terminal output. Widths are measured in terminal columns, so ANSI escape
+// sequences count as nothing and wide characters (CJK, most emoji) as two. Escape sequences are
+// never split, and continuation lines keep the indentation of the line they came from.
+
+const TAB_STOP: usize = 8;
+
+// A piece of a line: either visible text or an escape sequence, which takes no columns.
+enum Piece<'a> {
+    Text(&'a str),
+    Escape(&'a str)
+}
+
+// Splits off escape sequences: CSI (ESC [ ... final byte) and two-byte ESC x.
+fn pieces(line: &str) -> Vec<Piece<'_>> {
+    let mut out = Vec::new();
+    let mut rest = line;
+
+    while let Some(pos) = rest.find('\x1b') {
+        if pos > 0 {
+            out.push(Piece::Text(&rest[..pos]));
+        }
+        let seq = &rest[pos..];
+        let len = if seq[1..].starts_with('[') {
+            seq[2..].find(|c: char| ('\x40'..='\x7e').contains(&c)).map(|e| e + 3).unwrap_or(seq.len())
+        } else {
+            seq[1..].chars().next().map(|c| 1 + c.len_utf8()).unwrap_or(1)
+        };
+        out.push(Piece::Escape(&seq[..len]));
+        rest = &seq[len..];
+    }
+    if !rest.is_empty() {
+        out.push(Piece::Text(rest));
+    }
+    out
+}
+
+fn char_width(c: char, col: usize) -> usize {
+    if c == '\t' {
+        TAB_STOP - (col % TAB_STOP)
+    } else {
+        c.width().unwrap_or(0)
+    }
+}
+
+// The display width of s, ignoring escape sequences.
+pub fn visible_width(s: &str) -> usize {
+    let mut col = 0;
+    for p in pieces(s) {
+        if let Piece::Text(t) = p {
+            for c in t.chars() {
+                col += char_width(c, col);
+            }
+        }
+    }
+    col
+}
+
+struct LineWrapper {
+    width: usize,
+    indent: String,
+    indent_width: usize,
+    out: String,
+    col: usize,
+    // Whitespace waiting to be written before the next word. Dropped if the line breaks there.
+    pending_space: String,
+    // The word being built, escapes included, and its width.
+    word: String,
+    word_width: usize
+}
+
+impl LineWrapper {
+    fn new_line(&mut self) {
+        self.out.push('\n');
+        self.out.push_str(&self.indent);
+        self.col = self.indent_width;
+    }
+
+    fn flush_word(&mut self) {
+        if self.word.is_empty() {
+            return;
+        }
+
+        let space_width: usize = self.pending_space.chars().map(|c| char_width(c, self.col)).sum();
+        if self.col > self.indent_width && self.col + space_width + self.word_width > self.width {
+            self.new_line();
+        } else {
+            self.out.push_str(&self.pending_space);
+            self.col += space_width;
+        }
+        self.pending_space.clear();
+
+        if self.col + self.word_width <= self.width {
+            self.out.push_str(&self.word);
+            self.col += self.word_width;
+        } else {
+            // Longer than a whole line, so it has to be cut. Escapes go through whole.
+            let word = std::mem::take(&mut self.word);
+            for p in pieces(&word) {
+                match p {
+                    Piece::Escape(e) => self.out.push_str(e),
+                    Piece::Text(t) => for c in t.chars() {
+                        let w = char_width(c, self.col);
+                        if self.col + w > self.width && self.col > self.indent_width {
+                            self.new_line();
+                        }
+                        self.out.push(c);
+                        self.col += w;
+                    }
+                }
+            }
+        }
+        self.word.clear();
+        self.word_width = 0;
+    }
+}
+
+fn wrap_line(line: &str, width: usize, out: &mut String) {
+    if visible_width(line) <= width {
+        out.push_str(line);
+        return;
+    }
+
+    // Indentation is the leading whitespace of the visible text. Escapes in front of it are
+    // written as usual but aren't repeated on continuation lines.
+    let mut indent = String::new();
+    'outer: for p in pieces(line) {
+        if let Piece::Text(t) = p {
+            for c in t.chars() {
+                if c != ' ' && c != '\t' {
+                    break 'outer;
+                }
+                indent.push(c);
+            }
+        }
+    }
+    // An indent that leaves no room for text isn't worth keeping.
+    if visible_width(&indent) * 2 > width {
+        indent.clear();
+    }
+
+    let mut w = LineWrapper {
+        width,
+        indent_width: visible_width(&indent),
+        indent,
+        out: String::with_capacity(line.len() + 16),
+        col: 0,
+        pending_space: String::new(),
+        word: String::new(),
+        word_width: 0
+    };
+
+    let mut leading = true;
+    for p in pieces(line) {
+        match p {
+            Piece::Escape(e) if leading => w.out.push_str(e),
+            Piece::Escape(e) => w.word.push_str(e),
+            Piece::Text(t) => for c in t.chars() {
+                if c == ' ' || c == '\t' {
+                    if leading {
+                        // The original indentation goes out as-is.
+                        w.out.push(c);
+                        w.col += char_width(c, w.col);
+                    } else {
+                        w.flush_word();
+                        w.pending_space.push(c);
+                    }
+                } else {
+                    leading = false;
+                    w.word_width += char_width(c, w.col + w.word_width);
+                    w.word.push(c);
+                }
+            }
+        }
+    }
+    w.flush_word();
+    // Trailing whitespace is kept so that lines ending in escapes or spaces come out the same.
+    w.out.push_str(&w.pending_space);
+
+    out.push_str(&w.out);
+}
+
+// Wraps every line of s to width columns. \r\n and \n line endings are both kept as they are.
+pub fn wrap(s: &str, width: usize) -> String {
+    if width == 0 {
+        return s.to_string();
+    }
+
+    let mut out = String::with_capacity(s.len() + s.len() / width.max(1) * 2);
+    let mut lines = s.split('\n').peekable();
+    while let Some(line) = lines.next() {
+        let (body, cr) = match line.strip_suffix('\r') {
+            Some(b) => (b, "\r"),
+            None => (line, "")
+        };
+        wrap_line(body, width, &mut out);
+        out.push_str(cr);
+        if lines.peek().is_some() {
+            out.push('\n');
+        }
+    }
+    out
+}
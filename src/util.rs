@@ -59,6 +59,10 @@ pub enum ClientHelloStatus {
 
 pub fn check_tls_client_hello(data: &[u8]) -> ClientHelloStatus {
     if data.len() < 5 {
+        // A TLS record starts with the handshake type, so one byte is enough to rule it out.
+        if data.first().is_some_and(|b| *b != 0x16) {
+            return ClientHelloStatus::Invalid;
+        }
         return ClientHelloStatus::Partial;
     }
 
@@ -84,9 +84,22 @@ let defaultInPlugin = (function () {
         focusOnKeydown = bool;
     }
 
+    //
+    // the portal asks us to mask (or unmask) input, e.g. while a password is typed
+    var onSecretInput = function (args, kwargs) {
+        var fields = $(".inputfield, #inputfield");
+        if (args && args[0]) {
+            fields.addClass("secret");
+        } else {
+            fields.removeClass("secret");
+        }
+    }
+
     //
     // Mandatory plugin init function
     var init = function () {
+        window.Evennia.emitter.on("secret_input", onSecretInput);
+
         // Handle pressing the send button, this only applies to non-goldenlayout setups
         $("#inputsend")
             .bind("click", function (evnt) {
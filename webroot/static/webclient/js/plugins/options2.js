@@ -41,13 +41,32 @@ let options2 = (function () {
                                "</label>"
                              ].join("") );
 
+        checked = options["screenreader"] ? "checked='checked'" : "";
+        var screenreader = $( [ "<label>",
+                               "<input type='checkbox' data-setting='screenreader' " + checked + "'>",
+                               " Screen reader mode",
+                               "</label>"
+                             ].join("") );
+
+        // On unless turned off. Off, the portal sends the game's OOB messages as text instead.
+        checked = options["oob"] !== false ? "checked='checked'" : "";
+        var oob = $( [ "<label>",
+                               "<input type='checkbox' data-setting='oob' " + checked + "'>",
+                               " Receive OOB data",
+                               "</label>"
+                             ].join("") );
+
         gagprompt.on("change", onOptionCheckboxChanged);
         notifypopup.on("change", onOptionCheckboxChanged);
         notifysound.on("change", onOptionCheckboxChanged);
+        screenreader.on("change", onOptionCheckboxChanged);
+        oob.on("change", onOptionCheckboxChanged);
 
         parentdiv.append(gagprompt);
         parentdiv.append(notifypopup);
         parentdiv.append(notifysound);
+        parentdiv.append(screenreader);
+        parentdiv.append(oob);
     }
 
 